@@ -1,15 +1,24 @@
+use crate::callable::{Arity, Function};
+use crate::class::{Class, Instance};
 use crate::errors::Result;
-use crate::interpreter::EnvRef;
+use crate::generator::Generator;
+use crate::interpreter::Interpreter;
+use crate::iterator::IterRef;
+use crate::map::MapRef;
+use crate::module::Module;
 use crate::scanner::{Token, TokenType};
-use std::cell::RefCell;
+use crate::vm::object::{BoundMethod, Closure};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::convert::From;
 use std::fmt;
 use std::rc::Rc;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum UnaryOperator {
     Bang,
     Minus,
+    Tilde,
 }
 
 impl From<TokenType> for UnaryOperator {
@@ -17,6 +26,7 @@ impl From<TokenType> for UnaryOperator {
         match token {
             TokenType::Bang => UnaryOperator::Bang,
             TokenType::Minus => UnaryOperator::Minus,
+            TokenType::Tilde => UnaryOperator::Tilde,
             _ => panic!("Invalid unary operator {:?}", token),
         }
     }
@@ -27,6 +37,7 @@ impl fmt::Display for UnaryOperator {
         match *self {
             UnaryOperator::Bang => write!(f, "!"),
             UnaryOperator::Minus => write!(f, "-"),
+            UnaryOperator::Tilde => write!(f, "~"),
         }
     }
 }
@@ -35,6 +46,8 @@ impl fmt::Display for UnaryOperator {
 pub enum LogicalOperator {
     Or,
     And,
+    /// `??`, which only evaluates its right operand if the left one is nil
+    Coalesce,
 }
 
 impl From<TokenType> for LogicalOperator {
@@ -42,6 +55,7 @@ impl From<TokenType> for LogicalOperator {
         match token {
             TokenType::Or => LogicalOperator::Or,
             TokenType::And => LogicalOperator::And,
+            TokenType::QuestionQuestion => LogicalOperator::Coalesce,
             _ => panic!("Invalid logical operator {:?}", token),
         }
     }
@@ -52,11 +66,12 @@ impl fmt::Display for LogicalOperator {
         match *self {
             LogicalOperator::Or => write!(f, "or"),
             LogicalOperator::And => write!(f, "and"),
+            LogicalOperator::Coalesce => write!(f, "??"),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BinaryOperator {
     Equal,
     EqualEqual,
@@ -70,6 +85,14 @@ pub enum BinaryOperator {
     Plus,
     Star,
     Slash,
+    Percent,
+    StarStar,
+    TildeSlash,
+    Ampersand,
+    Pipe,
+    Caret,
+    LessLess,
+    GreaterGreater,
 }
 
 impl From<TokenType> for BinaryOperator {
@@ -87,6 +110,19 @@ impl From<TokenType> for BinaryOperator {
             TokenType::Plus => BinaryOperator::Plus,
             TokenType::Slash => BinaryOperator::Slash,
             TokenType::Star => BinaryOperator::Star,
+            TokenType::Percent => BinaryOperator::Percent,
+            TokenType::StarStar => BinaryOperator::StarStar,
+            TokenType::TildeSlash => BinaryOperator::TildeSlash,
+            TokenType::Ampersand => BinaryOperator::Ampersand,
+            TokenType::Pipe => BinaryOperator::Pipe,
+            TokenType::Caret => BinaryOperator::Caret,
+            TokenType::LessLess => BinaryOperator::LessLess,
+            TokenType::GreaterGreater => BinaryOperator::GreaterGreater,
+            TokenType::PlusEqual | TokenType::PlusPlus => BinaryOperator::Plus,
+            TokenType::MinusEqual | TokenType::MinusMinus => BinaryOperator::Minus,
+            TokenType::StarEqual => BinaryOperator::Star,
+            TokenType::SlashEqual => BinaryOperator::Slash,
+            TokenType::PercentEqual => BinaryOperator::Percent,
             _ => panic!("Invalid binary operator {:?}", token),
         }
     }
@@ -107,6 +143,14 @@ impl fmt::Display for BinaryOperator {
             BinaryOperator::Plus => write!(f, "+"),
             BinaryOperator::Slash => write!(f, "/"),
             BinaryOperator::Star => write!(f, "*"),
+            BinaryOperator::Percent => write!(f, "%"),
+            BinaryOperator::StarStar => write!(f, "**"),
+            BinaryOperator::TildeSlash => write!(f, "~/"),
+            BinaryOperator::Ampersand => write!(f, "&"),
+            BinaryOperator::Pipe => write!(f, "|"),
+            BinaryOperator::Caret => write!(f, "^"),
+            BinaryOperator::LessLess => write!(f, "<<"),
+            BinaryOperator::GreaterGreater => write!(f, ">>"),
         }
     }
 }
@@ -114,6 +158,7 @@ impl fmt::Display for BinaryOperator {
 #[derive(Debug, Clone, PartialEq)]
 pub struct UnaryExpr {
     pub op: UnaryOperator,
+    pub tok: Token,
     pub expr: Expr,
 }
 
@@ -127,6 +172,7 @@ impl fmt::Display for UnaryExpr {
 pub struct BinaryExpr {
     pub left: Expr,
     pub op: BinaryOperator,
+    pub tok: Token,
     pub right: Expr,
 }
 
@@ -136,6 +182,31 @@ impl fmt::Display for BinaryExpr {
     }
 }
 
+/// `target op= value`, or `++`/`--` with a value of 1. The target is a
+/// variable, property or index expression whose parts are evaluated once,
+/// before `value`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpdateExpr {
+    pub target: Expr,
+    pub op: BinaryOperator,
+    pub tok: Token,
+    pub value: Expr,
+    /// Postfix `x++` and `x--` produce the target's old value
+    pub postfix: bool,
+}
+
+impl fmt::Display for UpdateExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.tok.ty {
+            _ if self.postfix => write!(f, "{}{}", self.target, self.tok.lexeme),
+            TokenType::PlusPlus | TokenType::MinusMinus => {
+                write!(f, "{}{}", self.tok.lexeme, self.target)
+            }
+            _ => write!(f, "{} {} {}", self.target, self.tok.lexeme, self.value),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct LogicalExpr {
     pub left: Expr,
@@ -160,20 +231,89 @@ impl fmt::Display for Grouping {
     }
 }
 
-pub type BuitinFunc = fn(Vec<Value>) -> Result<Value>;
+/// Builtins get the interpreter so that any output they produce goes
+/// through its configured `Output`.
+pub type BuitinFunc = fn(&mut Interpreter, Vec<Value>) -> Result<Value>;
 
-#[derive(Debug, Clone, PartialEq)]
+/// The parameter names of a builtin, which calls can pass arguments by.
+pub type Params = &'static [&'static str];
+
+#[derive(Debug, Clone)]
 pub enum Value {
     Nil,
     Bool(bool),
     Number(f64),
     String(String),
-    BuiltinFunc(String, usize, BuitinFunc),
-    Func(Token, EnvRef, Vec<Token>, Box<Stmt>),
-    Return(Box<Value>),
+    BuiltinFunc(String, Params, BuitinFunc),
+    Func(Rc<Function>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+    List(ListRef),
+    Map(MapRef),
+    /// A builtin bound to the value it was looked up on, which is passed to
+    /// it as the first argument. The parameters don't include the receiver.
+    BuiltinMethod(Box<Value>, &'static str, Params, BuitinFunc),
+    /// A function compiled for the bytecode VM
+    Closure(Rc<Closure>),
+    /// A VM method together with the instance it was looked up on
+    BoundMethod(Rc<BoundMethod>),
+    /// The namespace of an imported module
+    Module(Rc<Module>),
+    /// A builtin iterator, as made for `for-in` loops and by `range()`
+    Iterator(IterRef),
+    /// The iterator returned by a call to a `fun*` function
+    Generator(Rc<Generator>),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::BuiltinFunc(a, _, _), Value::BuiltinFunc(b, _, _)) => a == b,
+            // Functions, classes and instances compare by identity
+            (Value::Func(a), Value::Func(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+            (Value::BuiltinMethod(a, x, _, _), Value::BuiltinMethod(b, y, _, _)) => {
+                x == y && a == b
+            }
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Value::Module(a), Value::Module(b)) => Rc::ptr_eq(a, b),
+            (Value::Iterator(a), Value::Iterator(b)) => Rc::ptr_eq(a, b),
+            (Value::Generator(a), Value::Generator(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 pub type ValRef = Rc<RefCell<Value>>;
+pub type ListRef = Rc<RefCell<Vec<Value>>>;
+
+thread_local! {
+    /// The lists and maps currently being printed.
+    static PRINTING: RefCell<HashSet<*const ()>> = RefCell::new(HashSet::new());
+}
+
+/// Prints the list or map at `ptr` with `items`, or as `cycle` if it is
+/// already being printed further out, so containers holding themselves
+/// don't recurse forever.
+fn write_nested<F>(f: &mut fmt::Formatter, ptr: *const (), cycle: &str, items: F) -> fmt::Result
+where
+    F: FnOnce(&mut fmt::Formatter) -> fmt::Result,
+{
+    if !PRINTING.with(|printing| printing.borrow_mut().insert(ptr)) {
+        return write!(f, "{}", cycle);
+    }
+    let res = items(f);
+    PRINTING.with(|printing| printing.borrow_mut().remove(&ptr));
+    res
+}
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -183,13 +323,48 @@ impl fmt::Display for Value {
             Value::Number(n) => write!(f, "{}", n),
             Value::String(ref s) => write!(f, "\"{}\"", s),
             Value::BuiltinFunc(ref name, _, _) => write!(f, "<built-in function {}>", name),
-            Value::Func(ref tok, _, _, _) => write!(f, "<function {}>", tok.lexeme),
-            Value::Return(ref val) => write!(f, "return {};", val),
+            Value::Func(ref func) => write!(f, "<fn {}>", func.decl.name.lexeme),
+            Value::Class(ref class) => write!(f, "{}", class.name),
+            Value::Instance(ref instance) => write!(f, "{} instance", instance.borrow().class.name),
+            Value::List(ref list) => write_nested(f, Rc::as_ptr(list).cast(), "[...]", |f| {
+                write!(f, "[")?;
+                for (i, value) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }),
+            Value::Map(ref map) => write_nested(f, Rc::as_ptr(map).cast(), "{...}", |f| {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key.to_value(), value)?;
+                }
+                write!(f, "}}")
+            }),
+            Value::BuiltinMethod(_, name, _, _) => write!(f, "<built-in method {}>", name),
+            Value::Closure(ref closure) => write!(f, "<fn {}>", closure.name()),
+            Value::BoundMethod(ref bound) => write!(f, "<fn {}>", bound.method.name()),
+            Value::Module(ref module) => write!(f, "<module {}>", module.name),
+            Value::Iterator(_) => write!(f, "<iterator>"),
+            Value::Generator(ref generator) => write!(f, "<generator {}>", generator.name),
         }
     }
 }
 
 impl Value {
+    /// The text `print` shows for this value, which leaves strings unquoted.
+    pub fn stringify(&self) -> String {
+        match *self {
+            Value::String(ref s) => s.clone(),
+            ref value => value.to_string(),
+        }
+    }
+
     pub fn is_truthy(&self) -> bool {
         match *self {
             Value::Nil => false,
@@ -202,6 +377,18 @@ impl Value {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Identifier {
     pub name: Token,
+    /// Number of scopes between the use and its declaration, filled in by
+    /// the resolver. `None` means the variable is global.
+    pub depth: Cell<Option<usize>>,
+}
+
+impl Identifier {
+    pub fn new(name: Token) -> Identifier {
+        Identifier {
+            name,
+            depth: Cell::new(None),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -210,10 +397,35 @@ pub enum Expr {
     Unary(Box<UnaryExpr>),
     Binary(Box<BinaryExpr>),
     Logical(Box<LogicalExpr>),
-    Call(Box<Expr>, Vec<Expr>),
+    /// `cond ? then : else`
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Token, Vec<Arg>),
+    /// A list literal and its opening bracket
+    List(Token, Vec<Expr>),
+    /// An interpolated string's parts, stringified and concatenated
+    Interpolate(Vec<Expr>),
+    /// A map literal's opening brace and its key-value pairs
+    Map(Token, Vec<(Expr, Expr)>),
+    /// `target[index]`, located at the opening bracket
+    Index(Box<Expr>, Token, Box<Expr>),
+    /// `target[index] = value`
+    SetIndex(Box<Expr>, Token, Box<Expr>, Box<Expr>),
+    Get(Box<Expr>, Token),
+    /// `object?.rest`, where `rest` is the remainder of the call chain built
+    /// on `Expr::Chained`. `rest` is skipped, giving nil, if `object` is nil.
+    Chain(Box<Expr>, Box<Expr>),
+    /// Stands for the object of the enclosing `Expr::Chain`
+    Chained,
+    Set(Box<Expr>, Token, Box<Expr>),
+    Super(Identifier, Token),
+    This(Identifier),
     Grouping(Box<Grouping>),
     Variable(Identifier),
     Assign(Identifier, Box<Expr>),
+    Update(Box<UpdateExpr>),
+    /// A `fun (params) { body }` or `(params) => body` expression, named
+    /// "anonymous"
+    Function(Rc<FunctionDecl>),
 }
 
 impl fmt::Display for Expr {
@@ -223,7 +435,10 @@ impl fmt::Display for Expr {
             Expr::Unary(ref v) => write!(f, "{}", v),
             Expr::Binary(ref v) => write!(f, "{}", v),
             Expr::Logical(ref v) => write!(f, "{}", v),
-            Expr::Call(ref callee, ref args) => {
+            Expr::Conditional(ref cond, ref then, ref otherwise) => {
+                write!(f, "({} ? {} : {})", cond, then, otherwise)
+            }
+            Expr::Call(ref callee, _, ref args) => {
                 write!(f, "{callee}(")?;
                 let mut loop_start = true;
                 for arg in args {
@@ -235,9 +450,50 @@ impl fmt::Display for Expr {
                 }
                 write!(f, ")")
             }
+            Expr::List(_, ref items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Expr::Map(_, ref entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+            Expr::Interpolate(ref parts) => {
+                write!(f, "(interpolate")?;
+                for part in parts {
+                    write!(f, " {}", part)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Index(ref target, _, ref index) => write!(f, "{}[{}]", target, index),
+            Expr::SetIndex(ref target, _, ref index, ref v) => {
+                write!(f, "{}[{}] = {}", target, index, v)
+            }
+            Expr::Get(ref object, ref name) => write!(f, "{}.{}", object, name.lexeme),
+            Expr::Chain(ref object, ref rest) => write!(f, "{}?{}", object, rest),
+            Expr::Chained => Ok(()),
+            Expr::Set(ref object, ref name, ref v) => {
+                write!(f, "{}.{} = {}", object, name.lexeme, v)
+            }
+            Expr::Super(_, ref method) => write!(f, "super.{}", method.lexeme),
+            Expr::This(_) => write!(f, "this"),
             Expr::Grouping(ref v) => write!(f, "{}", v),
             Expr::Variable(ref v) => write!(f, "{}", v.name.lexeme),
             Expr::Assign(ref id, ref v) => write!(f, "{} = {}", id.name.lexeme, v),
+            Expr::Update(ref u) => write!(f, "{}", u),
+            Expr::Function(ref decl) => write!(f, "<fn {}>", decl.name.lexeme),
         }
     }
 }
@@ -246,10 +502,133 @@ impl fmt::Display for Expr {
 pub enum Stmt {
     Expr(Expr),
     Print(Expr),
-    Decl(Identifier, Expr),
+    /// A `var` declaration, or a `const` one if the flag is set
+    Decl(Identifier, Expr, bool),
     Block(Vec<Stmt>),
-    Return(Expr),
+    Return(Token, Option<Expr>),
+    /// `yield value;` in a generator, and its keyword
+    Yield(Token, Option<Expr>),
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
-    While(Expr, Box<Stmt>),
-    Func(Token, Vec<Token>, Box<Stmt>),
+    /// A loop with its condition, body and, for desugared `for` loops, an
+    /// increment that runs after each iteration, even one cut short by
+    /// `continue`.
+    While(Expr, Box<Stmt>, Option<Expr>),
+    ForIn(Box<ForInStmt>),
+    Break(Token),
+    Continue(Token),
+    Func(Rc<FunctionDecl>),
+    Class(ClassDecl),
+    /// `throw value;` and its keyword
+    Throw(Token, Expr),
+    Try(Box<TryStmt>),
+    Import(ImportStmt),
+}
+
+/// `import "path" as name;`, `import { a, b } from "path";` or just
+/// `import "path";` to run a module for its side effects.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportStmt {
+    /// The string literal naming the module's file
+    pub path: Token,
+    /// The variable the whole module is bound to
+    pub alias: Option<Identifier>,
+    /// Top-level bindings of the module to bind under the same names
+    pub names: Vec<Identifier>,
+}
+
+impl ImportStmt {
+    pub fn module_path(&self) -> &str {
+        self.path.literal.as_deref().unwrap_or_default()
+    }
+}
+
+/// `for (name in iterable) body`. Each iteration binds `name` anew.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForInStmt {
+    pub name: Identifier,
+    /// The `in`, which errors from the iteration protocol are reported at
+    pub keyword: Token,
+    pub iterable: Expr,
+    pub body: Stmt,
+}
+
+/// `try { body } catch (name) { handler } finally { cleanup }`, where at
+/// least one of the `catch` and `finally` clauses is present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TryStmt {
+    pub keyword: Token,
+    pub body: Vec<Stmt>,
+    /// The variable the caught value is bound to and the handler's body
+    pub catch: Option<(Identifier, Vec<Stmt>)>,
+    /// Runs however the rest of the statement finishes
+    pub finally: Option<Vec<Stmt>>,
+}
+
+/// An argument of a call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Positional(Expr),
+    /// `...list`, passing each item of the list, and its `...`
+    Spread(Token, Expr),
+    /// `name: value`, passing the value to the parameter called `name`
+    Named(Token, Expr),
+}
+
+impl Arg {
+    /// The parameter a named argument is for.
+    pub fn name(&self) -> Option<&String> {
+        match *self {
+            Arg::Named(ref name, _) => Some(&name.lexeme),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Arg::Positional(ref value) => write!(f, "{}", value),
+            Arg::Spread(_, ref list) => write!(f, "...{}", list),
+            Arg::Named(ref name, ref value) => write!(f, "{}: {}", name.lexeme, value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: Token,
+    /// Evaluated in the callee's scope when the argument is left out
+    pub default: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDecl {
+    pub name: Token,
+    /// The parameters before the rest parameter. Those with defaults come
+    /// after all the others.
+    pub params: Vec<Param>,
+    /// `...name`, collecting the arguments after `params` into a list
+    pub rest: Option<Token>,
+    pub body: Vec<Stmt>,
+    /// Declared with `fun*` (or `*` for methods), so calls return a
+    /// generator instead of running the body
+    pub is_generator: bool,
+}
+
+impl FunctionDecl {
+    pub fn arity(&self) -> Arity {
+        let optional = self.params.iter().filter(|p| p.default.is_some()).count();
+        Arity {
+            required: self.params.len() - optional,
+            optional,
+            variadic: self.rest.is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassDecl {
+    pub name: Token,
+    pub superclass: Option<Identifier>,
+    pub methods: Vec<Rc<FunctionDecl>>,
 }
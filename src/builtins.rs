@@ -1,9 +1,22 @@
 use crate::ast::Value;
 use crate::errors::Result;
+use crate::interpreter::Interpreter;
+use crate::iterator;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn clock(_args: Vec<Value>) -> Result<Value> {
+/// Every builtin with the global name it is bound to, for either engine.
+pub fn globals() -> Vec<(&'static str, Value)> {
+    vec![
+        ("clock", Value::BuiltinFunc("clock".to_string(), &[], clock)),
+        (
+            "range",
+            Value::BuiltinFunc("range".to_string(), &["start", "end"], iterator::range),
+        ),
+    ]
+}
+
+pub fn clock(_interpreter: &mut Interpreter, _args: Vec<Value>) -> Result<Value> {
     Ok(Value::Number(
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
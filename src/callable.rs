@@ -1,48 +1,187 @@
 use std::cell::RefCell;
+use std::fmt;
 use std::rc::Rc;
 
 use crate::ast::*;
+use crate::class::Instance;
 use crate::errors::{ErrorKind, Result};
-use crate::interpretable::Interpretable;
-use crate::interpreter::{EnvRef, Environment};
+use crate::evaluable::Evaluable;
+use crate::generator::{self, Frame, Generator, TreeFrame};
+use crate::interpretable::{execute_block, Completion};
+use crate::interpreter::{EnvRef, Environment, Interpreter};
+use crate::list;
+use crate::scanner::Token;
+
+/// Arguments passed by name, in the order they appear in the call.
+pub type Named = Vec<(String, Value)>;
 
 pub trait Callable {
-    fn call(&self, env: EnvRef, args: Vec<Value>) -> Result<Value>;
+    /// `paren` is the call's closing parenthesis, used to locate errors.
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        args: Vec<Value>,
+        named: Named,
+    ) -> Result<Value>;
+}
+
+pub struct Function {
+    pub decl: Rc<FunctionDecl>,
+    pub closure: EnvRef,
+    pub is_initializer: bool,
+}
+
+impl Function {
+    pub fn new(decl: Rc<FunctionDecl>, closure: EnvRef, is_initializer: bool) -> Function {
+        Function {
+            decl,
+            closure,
+            is_initializer,
+        }
+    }
+
+    /// Returns a copy of this method whose closure has `this` bound to `instance`.
+    pub fn bind(&self, instance: Rc<RefCell<Instance>>) -> Function {
+        let mut env = Environment::wrap(self.closure.clone());
+        env.insert("this", Value::Instance(instance));
+        Function::new(
+            self.decl.clone(),
+            Rc::new(RefCell::new(env)),
+            self.is_initializer,
+        )
+    }
+}
+
+// The closure usually contains the function itself, so only print the name
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Function({})", self.decl.name.lexeme)
+    }
+}
+
+/// How many arguments a function takes.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Arity {
+    pub required: usize,
+    /// Parameters with a default value
+    pub optional: usize,
+    /// Whether a rest parameter takes any further arguments
+    pub variadic: bool,
+}
+
+impl Arity {
+    /// The number of parameters before the rest parameter.
+    pub fn params(&self) -> usize {
+        self.required + self.optional
+    }
+
+    pub fn check(&self, got: usize) -> Result<()> {
+        if got >= self.required && (self.variadic || got <= self.params()) {
+            return Ok(());
+        }
+        let expected = if self.variadic {
+            format!("at least {}", self.required)
+        } else if self.optional == 0 {
+            self.required.to_string()
+        } else {
+            format!("{} to {}", self.required, self.params())
+        };
+        Err(ErrorKind::EvaluateError(format!(
+            "Wrong number of arguments: Expected {}, got {}",
+            expected, got
+        )))
+    }
+}
+
+/// Matches the arguments of a call to the parameters named `params`: the
+/// positional ones in order, then the named ones by name. Returns the
+/// argument of each parameter, or `None` for those left to their default,
+/// and the positional arguments left over for a rest parameter.
+pub(crate) fn match_args<S: AsRef<str>>(
+    params: &[S],
+    arity: Arity,
+    mut args: Vec<Value>,
+    named: Named,
+) -> Result<(Vec<Option<Value>>, Vec<Value>)> {
+    if named.is_empty() {
+        arity.check(args.len())?;
+    } else if args.len() > params.len() && !arity.variadic {
+        // Too many positional arguments
+        arity.check(args.len() + named.len())?;
+    }
+    let rest = args.split_off(args.len().min(params.len()));
+    let mut values: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+    values.resize(params.len(), None);
+    for (name, value) in named {
+        let i = params
+            .iter()
+            .position(|param| param.as_ref() == name)
+            .ok_or_else(|| ErrorKind::EvaluateError(format!("No parameter named '{}'.", name)))?;
+        if values[i].replace(value).is_some() {
+            return Err(ErrorKind::EvaluateError(format!(
+                "Got more than one argument for parameter '{}'.",
+                name
+            )));
+        }
+    }
+    if let Some(i) = values[..arity.required].iter().position(Option::is_none) {
+        return Err(ErrorKind::EvaluateError(format!(
+            "Missing argument for parameter '{}'.",
+            params[i].as_ref()
+        )));
+    }
+    Ok((values, rest))
+}
+
+/// The arguments of a call to a builtin taking `params`, in order.
+pub(crate) fn builtin_args(params: Params, args: Vec<Value>, named: Named) -> Result<Vec<Value>> {
+    let arity = Arity {
+        required: params.len(),
+        ..Arity::default()
+    };
+    if named.is_empty() {
+        arity.check(args.len())?;
+        return Ok(args);
+    }
+    let (values, _) = match_args(params, arity, args, named)?;
+    Ok(values.into_iter().flatten().collect())
 }
 
 impl Callable for Value {
-    fn call(&self, _env: EnvRef, args: Vec<Value>) -> Result<Value> {
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        args: Vec<Value>,
+        named: Named,
+    ) -> Result<Value> {
         match *self {
-            Value::BuiltinFunc(_, ref arity, ref func) => {
-                if *arity != args.len() {
-                    Err(ErrorKind::EvaluateError(format!(
-                        "Wrong number of arguments: Expected {}, got {}",
-                        arity,
-                        args.len()
-                    )))
-                } else {
-                    func(args)
+            Value::BuiltinFunc(_, params, ref func) => {
+                let args = builtin_args(params, args, named)?;
+                func(interpreter, args)
+            }
+            Value::BuiltinMethod(ref receiver, _, params, func) => {
+                let mut args = builtin_args(params, args, named)?;
+                if let Some(generator) = generator::runs_on_call(self) {
+                    generator::advance(interpreter, generator, paren.line)?;
                 }
+                args.insert(0, (**receiver).clone());
+                func(interpreter, args)
             }
-            Value::Func(_, ref closure, ref params, ref block) => {
-                if params.len() != args.len() {
-                    Err(ErrorKind::EvaluateError(format!(
-                        "Wrong number of arguments: Expected {}, got {}",
-                        params.len(),
-                        args.len()
-                    )))
-                } else {
-                    let funcenv = Rc::new(RefCell::new(Environment::wrap(closure.clone())));
-                    for (param, value) in params.iter().zip(args.into_iter()) {
-                        funcenv.borrow_mut().insert(&param.lexeme, value);
+            Value::Func(ref func) => call_function(interpreter, func, paren, args, named),
+            Value::Class(ref class) => {
+                let instance = Rc::new(RefCell::new(Instance::new(class.clone())));
+                match class.find_method("init") {
+                    Some(Value::Func(init)) => {
+                        let init = init.bind(instance.clone());
+                        call_function(interpreter, &init, paren, args, named)?;
                     }
-                    let res = block.interpret(funcenv);
-
-                    match res {
-                        Ok(Value::Return(x)) => Ok(*x),
-                        _ => res,
+                    _ => {
+                        builtin_args(&[], args, named)?;
                     }
                 }
+                Ok(Value::Instance(instance))
             }
             _ => Err(ErrorKind::EvaluateError(format!(
                 "{} is not a valid function",
@@ -51,3 +190,75 @@ impl Callable for Value {
         }
     }
 }
+
+/// Evaluates the defaults of the parameters flagged in `missing`, which got
+/// no arguments, in the callee's scope. Each can refer to the ones before.
+pub(crate) fn bind_defaults(
+    interpreter: &mut Interpreter,
+    decl: &FunctionDecl,
+    env: &EnvRef,
+    missing: &[bool],
+) -> Result<()> {
+    for (param, _) in decl.params.iter().zip(missing).filter(|(_, &m)| m) {
+        let default = param
+            .default
+            .as_ref()
+            .expect("missing argument without a default");
+        let value = default.evaluate(interpreter, env.clone())?;
+        env.borrow_mut().insert(&param.name.lexeme, value);
+    }
+    Ok(())
+}
+
+fn call_function(
+    interpreter: &mut Interpreter,
+    func: &Function,
+    paren: &Token,
+    args: Vec<Value>,
+    named: Named,
+) -> Result<Value> {
+    let decl = &func.decl;
+    let names: Vec<&str> = decl.params.iter().map(|p| p.name.lexeme.as_str()).collect();
+    let (values, rest) = match_args(&names, decl.arity(), args, named)?;
+    let missing: Vec<bool> = values.iter().map(Option::is_none).collect();
+    let funcenv = Rc::new(RefCell::new(Environment::wrap(func.closure.clone())));
+    for (param, value) in decl.params.iter().zip(values) {
+        if let Some(value) = value {
+            funcenv.borrow_mut().insert(&param.name.lexeme, value);
+        }
+    }
+    if let Some(ref rest_param) = decl.rest {
+        funcenv
+            .borrow_mut()
+            .insert(&rest_param.lexeme, list::new(rest));
+    }
+
+    if decl.is_generator {
+        let frame = TreeFrame::new(decl.clone(), funcenv, missing);
+        let generator = Generator::new(&decl.name.lexeme, Frame::Tree(frame));
+        return Ok(Value::Generator(Rc::new(generator)));
+    }
+
+    interpreter.push_frame(&decl.name.lexeme, paren.line)?;
+    let res = bind_defaults(interpreter, decl, &funcenv, &missing)
+        .and_then(|_| execute_block(&decl.body, interpreter, funcenv))
+        .map_err(|e| interpreter.with_stack_trace(e));
+    interpreter.pop_frame();
+    let res = res?;
+
+    if func.is_initializer {
+        // init() always hands back the instance, even after an early `return;`
+        return Ok(func
+            .closure
+            .borrow()
+            .get_at(Some(0), "this")
+            .map(|this| this.borrow().clone())
+            .unwrap_or(Value::Nil));
+    }
+    match res {
+        Completion::Return(value) => Ok(value),
+        // Falling off the end of a function returns nil. The parser keeps
+        // `break` and `continue` inside loops, so they can't get here.
+        Completion::Normal(_) | Completion::Break | Completion::Continue => Ok(Value::Nil),
+    }
+}
@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::Value;
+use crate::errors::{ErrorKind, Result};
+use crate::vm::object::BoundMethod;
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub superclass: Option<Rc<Class>>,
+    /// Methods are `Value::Func`s for the tree-walker and `Value::Closure`s
+    /// for the VM.
+    pub methods: HashMap<String, Value>,
+}
+
+impl Class {
+    pub fn new(
+        name: &str,
+        superclass: Option<Rc<Class>>,
+        methods: HashMap<String, Value>,
+    ) -> Class {
+        Class {
+            name: name.to_owned(),
+            superclass,
+            methods,
+        }
+    }
+
+    pub fn find_method(&self, name: &str) -> Option<Value> {
+        if let Some(method) = self.methods.get(name) {
+            return Some(method.clone());
+        }
+        self.superclass
+            .as_ref()
+            .and_then(|superclass| superclass.find_method(name))
+    }
+}
+
+/// Binds `this` in `method` to `instance`.
+pub fn bind(method: Value, instance: Rc<RefCell<Instance>>) -> Value {
+    match method {
+        Value::Func(func) => Value::Func(Rc::new(func.bind(instance))),
+        Value::Closure(method) => Value::BoundMethod(Rc::new(BoundMethod {
+            receiver: Value::Instance(instance),
+            method,
+        })),
+        method => method,
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<Class>,
+    fields: HashMap<String, Value>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Instance {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+
+    /// Looks up a field, falling back to a method bound to `instance`.
+    pub fn get(instance: &Rc<RefCell<Instance>>, name: &str) -> Result<Value> {
+        if let Some(value) = instance.borrow().fields.get(name) {
+            return Ok(value.clone());
+        }
+        let method = instance.borrow().class.find_method(name);
+        match method {
+            Some(method) => Ok(bind(method, instance.clone())),
+            None => Err(undefined_property(name)),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: Value) {
+        self.fields.insert(name.to_owned(), value);
+    }
+}
+
+pub fn undefined_property(name: &str) -> ErrorKind {
+    ErrorKind::EvaluateError(format!("Undefined property '{}'.", name))
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use crate::scanner::Token;
 use thiserror::Error;
 
@@ -7,10 +9,62 @@ pub enum ErrorKind {
     ScanError(usize, String),
     #[error("Error at line {} at '{}': {t}", tok.line, tok.lexeme)]
     ParseError { tok: Token, t: String },
+    #[error("Error at line {} at '{}': {t}", tok.line, tok.lexeme)]
+    ResolveError { tok: Token, t: String },
+    /// A runtime error that hasn't been attributed to a token yet
     #[error("Error: {0}")]
     EvaluateError(String),
+    #[error("Error at line {}, column {}: {t}{}", tok.line, tok.col, StackTrace(trace))]
+    RuntimeError {
+        tok: Token,
+        t: String,
+        trace: Vec<TraceFrame>,
+    },
     #[error("IO Error: {0}")]
     IO(#[from] ::std::io::Error),
 }
 
+impl ErrorKind {
+    /// Attributes an `EvaluateError` to `tok`; other errors are returned unchanged.
+    pub fn at(self, tok: &Token) -> ErrorKind {
+        match self {
+            ErrorKind::EvaluateError(t) => ErrorKind::RuntimeError {
+                tok: tok.clone(),
+                t,
+                trace: vec![],
+            },
+            e => e,
+        }
+    }
+}
+
+/// One active call at the time of a runtime error, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// The line being executed in this frame
+    pub line: usize,
+    /// The function's name, or `None` for top-level code
+    pub function: Option<String>,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.function {
+            Some(ref name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+struct StackTrace<'a>(&'a [TraceFrame]);
+
+impl fmt::Display for StackTrace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for frame in self.0 {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
 pub type Result<T> = std::result::Result<T, ErrorKind>;
@@ -1,138 +1,446 @@
 use crate::ast::*;
-use crate::callable::Callable;
+use crate::callable::{Callable, Function};
+use crate::class::{bind, undefined_property, Instance};
 use crate::errors::{ErrorKind, Result};
-use crate::interpreter::EnvRef;
+use crate::generator;
+use crate::interpreter::{EnvRef, Interpreter};
+use crate::iterator;
+use crate::list;
+use crate::map::{self, Key, Map};
+use std::rc::Rc;
 
 pub trait Evaluable {
-    fn evaluate(&self, env: EnvRef) -> Result<Value>;
+    fn evaluate(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Value>;
 }
 
 impl Evaluable for Expr {
-    fn evaluate(&self, env: EnvRef) -> Result<Value> {
+    fn evaluate(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Value> {
         match *self {
             Expr::Literal(ref v) => Ok(v.clone()),
-            Expr::Unary(ref u) => u.evaluate(env),
-            Expr::Binary(ref b) => b.evaluate(env),
-            Expr::Logical(ref l) => l.evaluate(env),
-            Expr::Grouping(ref g) => g.evaluate(env),
-            Expr::Variable(ref id) => env
-                .borrow()
-                .get(&id.name.lexeme)
-                .map(|v| v.borrow().clone())
-                .ok_or(ErrorKind::EvaluateError(format!(
-                    "Undefined variable: {}",
-                    id.name.lexeme
-                ))),
+            Expr::Unary(ref u) => u.evaluate(interpreter, env).map_err(|e| e.at(&u.tok)),
+            Expr::Binary(ref b) => b.evaluate(interpreter, env).map_err(|e| e.at(&b.tok)),
+            Expr::Logical(ref l) => l.evaluate(interpreter, env),
+            Expr::Conditional(ref cond, ref then, ref otherwise) => {
+                if cond.evaluate(interpreter, env.clone())?.is_truthy() {
+                    then.evaluate(interpreter, env)
+                } else {
+                    otherwise.evaluate(interpreter, env)
+                }
+            }
+            Expr::Grouping(ref g) => g.evaluate(interpreter, env),
+            Expr::Variable(ref id) => {
+                lookup(&env, id.depth.get(), &id.name.lexeme).map_err(|e| e.at(&id.name))
+            }
             Expr::Assign(ref id, ref e) => {
-                let value = e.evaluate(env.clone())?;
-                env.borrow_mut()
-                    .update(&id.name.lexeme, value)
-                    .ok_or(ErrorKind::EvaluateError(format!(
-                        "Undefined variable: {}",
-                        id.name.lexeme
-                    )))
+                let value = e.evaluate(interpreter, env.clone())?;
+                env.borrow()
+                    .assign_at(id.depth.get(), &id.name.lexeme, value)
+                    .map_err(|e| e.at(&id.name))
+            }
+            Expr::Get(ref object, ref name) => {
+                let object = object.evaluate(interpreter, env)?;
+                if let Some(generator) = generator::runs_on_get(&object, &name.lexeme) {
+                    generator::advance(interpreter, generator, name.line)
+                        .map_err(|e| e.at(name))?;
+                }
+                get_property(object, &name.lexeme).map_err(|e| e.at(name))
+            }
+            Expr::Chain(ref object, ref rest) => {
+                let object = object.evaluate(interpreter, env.clone())?;
+                if object == Value::Nil {
+                    return Ok(Value::Nil);
+                }
+                interpreter.chained.push(object);
+                let value = rest.evaluate(interpreter, env);
+                interpreter.chained.pop();
+                value
+            }
+            Expr::Chained => Ok(interpreter
+                .chained
+                .last()
+                .cloned()
+                .expect("chained value outside of an optional chain")),
+            Expr::List(_, ref items) => {
+                let mut values = vec![];
+                for item in items {
+                    values.push(item.evaluate(interpreter, env.clone())?);
+                }
+                Ok(list::new(values))
+            }
+            Expr::Interpolate(ref parts) => {
+                let mut s = String::new();
+                for part in parts {
+                    s.push_str(&part.evaluate(interpreter, env.clone())?.stringify());
+                }
+                Ok(Value::String(s))
+            }
+            Expr::Map(ref brace, ref entries) => {
+                let mut map = Map::default();
+                for (key, value) in entries {
+                    let key = key.evaluate(interpreter, env.clone())?;
+                    let value = value.evaluate(interpreter, env.clone())?;
+                    map.insert(Key::new(&key).map_err(|e| e.at(brace))?, value);
+                }
+                Ok(map::new(map))
+            }
+            Expr::Index(ref target, ref bracket, ref index) => {
+                let target = target.evaluate(interpreter, env.clone())?;
+                let index = index.evaluate(interpreter, env)?;
+                get_index(&target, &index).map_err(|e| e.at(bracket))
+            }
+            Expr::SetIndex(ref target, ref bracket, ref index, ref value) => {
+                let target = target.evaluate(interpreter, env.clone())?;
+                let index = index.evaluate(interpreter, env.clone())?;
+                let value = value.evaluate(interpreter, env)?;
+                set_index(&target, &index, value).map_err(|e| e.at(bracket))
+            }
+            Expr::Update(ref u) => u.evaluate(interpreter, env),
+            Expr::Function(ref decl) => Ok(Value::Func(Rc::new(Function::new(
+                decl.clone(),
+                env,
+                false,
+            )))),
+            Expr::Set(ref object, ref name, ref value) => {
+                match object.evaluate(interpreter, env.clone())? {
+                    Value::Instance(instance) => {
+                        let value = value.evaluate(interpreter, env)?;
+                        instance.borrow_mut().set(&name.lexeme, value.clone());
+                        Ok(value)
+                    }
+                    Value::Module(_) => Err(read_only_module().at(name)),
+                    _ => Err(
+                        ErrorKind::EvaluateError("Only instances have fields.".to_string())
+                            .at(name),
+                    ),
+                }
+            }
+            Expr::Super(ref keyword, ref method) => {
+                // `this` lives in the scope just inside the one holding `super`
+                let depth = keyword.depth.get();
+                let superclass = lookup(&env, depth, "super").map_err(|e| e.at(&keyword.name))?;
+                let this =
+                    lookup(&env, depth.map(|d| d - 1), "this").map_err(|e| e.at(&keyword.name))?;
+                match (superclass, this) {
+                    (Value::Class(superclass), Value::Instance(instance)) => {
+                        match superclass.find_method(&method.lexeme) {
+                            Some(func) => Ok(bind(func, instance)),
+                            None => Err(undefined_property(&method.lexeme).at(method)),
+                        }
+                    }
+                    _ => Err(ErrorKind::EvaluateError(
+                        "Can't use 'super' outside of a method.".to_string(),
+                    )
+                    .at(&keyword.name)),
+                }
             }
-            Expr::Call(ref expr, ref args) => {
-                let func = expr.evaluate(env.clone())?;
+            Expr::This(ref keyword) => {
+                lookup(&env, keyword.depth.get(), "this").map_err(|e| e.at(&keyword.name))
+            }
+            Expr::Call(ref expr, ref paren, ref args) => {
+                let func = expr.evaluate(interpreter, env.clone())?;
                 let mut values = vec![];
+                let mut named = vec![];
                 for arg in args {
-                    values.push(arg.evaluate(env.clone())?)
+                    match *arg {
+                        Arg::Positional(ref value) => {
+                            values.push(value.evaluate(interpreter, env.clone())?)
+                        }
+                        Arg::Spread(ref tok, ref list) => {
+                            let list = list.evaluate(interpreter, env.clone())?;
+                            values.extend(spread(&list).map_err(|e| e.at(tok))?);
+                        }
+                        Arg::Named(ref name, ref value) => {
+                            let value = value.evaluate(interpreter, env.clone())?;
+                            named.push((name.lexeme.clone(), value));
+                        }
+                    }
                 }
-                func.call(env, values)
+                func.call(interpreter, paren, values, named)
+                    .map_err(|e| e.at(paren))
             }
         }
     }
 }
 
 impl Evaluable for UnaryExpr {
-    fn evaluate(&self, env: EnvRef) -> Result<Value> {
-        match self.op {
-            UnaryOperator::Bang => Ok(Value::Bool(!self.expr.evaluate(env)?.is_truthy())),
-            UnaryOperator::Minus => match self.expr.evaluate(env)? {
-                Value::Number(n) => Ok(Value::Number(-n)),
-                x => Err(ErrorKind::EvaluateError(format!("Can't negate {}", x))),
-            },
-        }
+    fn evaluate(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Value> {
+        let value = self.expr.evaluate(interpreter, env)?;
+        unary(self.op, value)
     }
 }
 
 impl Evaluable for BinaryExpr {
-    fn evaluate(&self, env: EnvRef) -> Result<Value> {
-        let left = self.left.evaluate(env.clone())?;
-        let right = self.right.evaluate(env.clone())?;
-
-        match self.op {
-            BinaryOperator::Minus
-            | BinaryOperator::Slash
-            | BinaryOperator::Star
-            | BinaryOperator::Less
-            | BinaryOperator::Greater
-            | BinaryOperator::LessEqual
-            | BinaryOperator::GreaterEqual => {
-                let left = number(&left)?;
-                let right = number(&right)?;
-                let value = match self.op {
-                    BinaryOperator::Minus => Value::Number(left - right),
-                    BinaryOperator::Star => Value::Number(left * right),
-                    BinaryOperator::Slash => Value::Number(left / right),
-                    BinaryOperator::Less => Value::Bool(left < right),
-                    BinaryOperator::LessEqual => Value::Bool(left <= right),
-                    BinaryOperator::Greater => Value::Bool(left > right),
-                    BinaryOperator::GreaterEqual => Value::Bool(left >= right),
-                    _ => Value::Nil,
-                };
-                Ok(value)
-            }
-            BinaryOperator::Plus => {
-                if let Value::Number(l) = left {
-                    if let Value::Number(r) = right {
-                        Ok(Value::Number(l + r))
-                    } else if let Value::String(r) = right {
-                        Ok(Value::String(format!("{}{}", l, r)))
-                    } else {
-                        Err(ErrorKind::EvaluateError(format!(
-                            "Can't add {} to a number",
-                            right
-                        )))
-                    }
-                } else if let Value::String(l) = left {
-                    if let Value::String(r) = right {
-                        Ok(Value::String(l + &r))
-                    } else if let Value::Number(r) = right {
-                        Ok(Value::String(format!("{}{}", l, r)))
-                    } else {
-                        Ok(Value::String(format!("{}{}", l, right)))
-                    }
+    fn evaluate(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Value> {
+        let left = self.left.evaluate(interpreter, env.clone())?;
+        let right = self.right.evaluate(interpreter, env.clone())?;
+        binary(self.op, left, right)
+    }
+}
+
+/// The arguments a `...list` argument stands for. Shared with the VM.
+pub fn spread(list: &Value) -> Result<Vec<Value>> {
+    match *list {
+        Value::List(ref list) => Ok(list.borrow().clone()),
+        ref value => Err(ErrorKind::EvaluateError(format!(
+            "Can only spread lists, instead got: {}",
+            value
+        ))),
+    }
+}
+
+/// Reads the property `name` of `object`. Shared with the VM.
+pub fn get_property(object: Value, name: &str) -> Result<Value> {
+    match object {
+        Value::Instance(instance) => Instance::get(&instance, name),
+        Value::List(ref list) => list::method(list, name).ok_or_else(|| undefined_property(name)),
+        Value::Map(ref map) => map::method(map, name).ok_or_else(|| undefined_property(name)),
+        Value::Module(ref module) => module.get(name).ok_or_else(|| undefined_property(name)),
+        Value::Iterator(ref it) => {
+            iterator::property(it, name).ok_or_else(|| undefined_property(name))
+        }
+        Value::Generator(ref generator) => {
+            generator::property(generator, name).ok_or_else(|| undefined_property(name))
+        }
+        _ => Err(ErrorKind::EvaluateError(
+            "Only instances have properties.".to_string(),
+        )),
+    }
+}
+
+/// Sets the field `name` of `object`, returning `value`. Shared with the VM.
+pub fn set_property(object: Value, name: &str, value: Value) -> Result<Value> {
+    match object {
+        Value::Instance(instance) => {
+            instance.borrow_mut().set(name, value.clone());
+            Ok(value)
+        }
+        Value::Module(_) => Err(read_only_module()),
+        _ => Err(ErrorKind::EvaluateError(
+            "Only instances have fields.".to_string(),
+        )),
+    }
+}
+
+/// Module namespaces can only be read from; a module's own code changes
+/// its bindings.
+fn read_only_module() -> ErrorKind {
+    ErrorKind::EvaluateError("Can't assign to module bindings.".to_string())
+}
+
+/// Reads `target[index]`. Shared with the VM.
+pub fn get_index(target: &Value, index: &Value) -> Result<Value> {
+    match *target {
+        Value::List(ref list) => {
+            let list = list.borrow();
+            let i = list::index(index, list.len(), false)?;
+            Ok(list[i].clone())
+        }
+        Value::Map(ref map) => {
+            let key = Key::new(index)?;
+            let map = map.borrow();
+            map.get(&key).cloned().ok_or_else(|| map::missing_key(&key))
+        }
+        _ => Err(not_indexable(target)),
+    }
+}
+
+/// Performs `target[index] = value`, returning `value`. Shared with the VM.
+pub fn set_index(target: &Value, index: &Value, value: Value) -> Result<Value> {
+    match *target {
+        Value::List(ref list) => {
+            let mut list = list.borrow_mut();
+            let i = list::index(index, list.len(), false)?;
+            list[i] = value.clone();
+            Ok(value)
+        }
+        Value::Map(ref map) => {
+            map.borrow_mut().insert(Key::new(index)?, value.clone());
+            Ok(value)
+        }
+        _ => Err(not_indexable(target)),
+    }
+}
+
+fn not_indexable(target: &Value) -> ErrorKind {
+    ErrorKind::EvaluateError(format!("Can't index into {}.", target))
+}
+
+/// Applies a unary operator. Shared with the VM so both engines agree.
+pub fn unary(op: UnaryOperator, value: Value) -> Result<Value> {
+    match op {
+        UnaryOperator::Bang => Ok(Value::Bool(!value.is_truthy())),
+        UnaryOperator::Minus => match value {
+            Value::Number(n) => Ok(Value::Number(-n)),
+            x => Err(ErrorKind::EvaluateError(format!("Can't negate {}", x))),
+        },
+        UnaryOperator::Tilde => Ok(Value::Number(!integer(&value)? as f64)),
+    }
+}
+
+/// Applies a binary operator. Shared with the VM so both engines agree.
+pub fn binary(op: BinaryOperator, left: Value, right: Value) -> Result<Value> {
+    match op {
+        BinaryOperator::Minus
+        | BinaryOperator::Slash
+        | BinaryOperator::Star
+        | BinaryOperator::Percent
+        | BinaryOperator::StarStar
+        | BinaryOperator::TildeSlash
+        | BinaryOperator::Less
+        | BinaryOperator::Greater
+        | BinaryOperator::LessEqual
+        | BinaryOperator::GreaterEqual => {
+            let left = number(&left)?;
+            let right = number(&right)?;
+            let value = match op {
+                BinaryOperator::Minus => Value::Number(left - right),
+                BinaryOperator::Star => Value::Number(left * right),
+                BinaryOperator::Slash => Value::Number(left / right),
+                BinaryOperator::Percent => Value::Number(modulo(left, right)),
+                BinaryOperator::StarStar => Value::Number(left.powf(right)),
+                BinaryOperator::TildeSlash => Value::Number((left / right).floor()),
+                BinaryOperator::Less => Value::Bool(left < right),
+                BinaryOperator::LessEqual => Value::Bool(left <= right),
+                BinaryOperator::Greater => Value::Bool(left > right),
+                BinaryOperator::GreaterEqual => Value::Bool(left >= right),
+                _ => Value::Nil,
+            };
+            Ok(value)
+        }
+        BinaryOperator::Plus => {
+            if let Value::Number(l) = left {
+                if let Value::Number(r) = right {
+                    Ok(Value::Number(l + r))
                 } else if let Value::String(r) = right {
-                    Ok(Value::String(format!("{}{}", left, r)))
+                    Ok(Value::String(format!("{}{}", l, r)))
                 } else {
                     Err(ErrorKind::EvaluateError(format!(
-                        "Can't add {} and {}",
-                        left, right
+                        "Can't add {} to a number",
+                        right
                     )))
                 }
+            } else if let Value::String(l) = left {
+                if let Value::String(r) = right {
+                    Ok(Value::String(l + &r))
+                } else if let Value::Number(r) = right {
+                    Ok(Value::String(format!("{}{}", l, r)))
+                } else {
+                    Ok(Value::String(format!("{}{}", l, right)))
+                }
+            } else if let Value::String(r) = right {
+                Ok(Value::String(format!("{}{}", left, r)))
+            } else {
+                Err(ErrorKind::EvaluateError(format!(
+                    "Can't add {} and {}",
+                    left, right
+                )))
             }
-            BinaryOperator::EqualEqual => Ok(Value::Bool(left == right)),
-            BinaryOperator::BangEqual => Ok(Value::Bool(left != right)),
-            BinaryOperator::Equal => Ok(Value::Nil),
         }
+        BinaryOperator::Ampersand | BinaryOperator::Pipe | BinaryOperator::Caret => {
+            let left = integer(&left)?;
+            let right = integer(&right)?;
+            let value = match op {
+                BinaryOperator::Ampersand => left & right,
+                BinaryOperator::Pipe => left | right,
+                _ => left ^ right,
+            };
+            Ok(Value::Number(value as f64))
+        }
+        BinaryOperator::LessLess | BinaryOperator::GreaterGreater => {
+            let left = integer(&left)?;
+            let right = integer(&right)?;
+            if !(0..64).contains(&right) {
+                return Err(ErrorKind::EvaluateError(format!(
+                    "Shift amount must be between 0 and 63, instead got: {}",
+                    right
+                )));
+            }
+            let value = if op == BinaryOperator::LessLess {
+                left << right
+            } else {
+                left >> right
+            };
+            Ok(Value::Number(value as f64))
+        }
+        BinaryOperator::EqualEqual => Ok(Value::Bool(left == right)),
+        BinaryOperator::BangEqual => Ok(Value::Bool(left != right)),
+        BinaryOperator::Equal => Ok(Value::Nil),
     }
 }
 
-impl Evaluable for LogicalExpr {
-    fn evaluate(&self, env: EnvRef) -> Result<Value> {
-        let left = self.left.evaluate(env.clone())?;
-        if self.op == LogicalOperator::Or {
-            if left.is_truthy() {
-                return Ok(left);
+impl UpdateExpr {
+    /// Evaluates the value and combines it with the target's old value.
+    fn apply(&self, interpreter: &mut Interpreter, env: EnvRef, old: &Value) -> Result<Value> {
+        let value = self.value.evaluate(interpreter, env)?;
+        binary(self.op, old.clone(), value).map_err(|e| e.at(&self.tok))
+    }
+
+    fn result(&self, old: Value, new: Value) -> Value {
+        if self.postfix {
+            old
+        } else {
+            new
+        }
+    }
+}
+
+impl Evaluable for UpdateExpr {
+    fn evaluate(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Value> {
+        match self.target {
+            Expr::Variable(ref id) => {
+                let name = &id.name.lexeme;
+                let old = lookup(&env, id.depth.get(), name).map_err(|e| e.at(&id.name))?;
+                let new = self.apply(interpreter, env.clone(), &old)?;
+                env.borrow()
+                    .assign_at(id.depth.get(), name, new.clone())
+                    .map_err(|e| e.at(&id.name))?;
+                Ok(self.result(old, new))
+            }
+            Expr::Get(ref object, ref name) => {
+                let object = object.evaluate(interpreter, env.clone())?;
+                let old = get_property(object.clone(), &name.lexeme).map_err(|e| e.at(name))?;
+                let new = self.apply(interpreter, env, &old)?;
+                set_property(object, &name.lexeme, new.clone()).map_err(|e| e.at(name))?;
+                Ok(self.result(old, new))
+            }
+            Expr::Index(ref target, ref bracket, ref index) => {
+                let target = target.evaluate(interpreter, env.clone())?;
+                let index = index.evaluate(interpreter, env.clone())?;
+                let old = get_index(&target, &index).map_err(|e| e.at(bracket))?;
+                let new = self.apply(interpreter, env, &old)?;
+                set_index(&target, &index, new.clone()).map_err(|e| e.at(bracket))?;
+                Ok(self.result(old, new))
             }
-        } else if !left.is_truthy() {
+            _ => unreachable!("the parser only builds updates of assignable targets"),
+        }
+    }
+}
+
+impl Evaluable for LogicalExpr {
+    fn evaluate(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Value> {
+        let left = self.left.evaluate(interpreter, env.clone())?;
+        let short_circuits = match self.op {
+            LogicalOperator::Or => left.is_truthy(),
+            LogicalOperator::And => !left.is_truthy(),
+            LogicalOperator::Coalesce => left != Value::Nil,
+        };
+        if short_circuits {
             return Ok(left);
         }
-        self.right.evaluate(env.clone())
+        self.right.evaluate(interpreter, env.clone())
     }
 }
 
+fn lookup(env: &EnvRef, depth: Option<usize>, name: &str) -> Result<Value> {
+    env.borrow()
+        .get_at(depth, name)
+        .map(|v| v.borrow().clone())
+        .ok_or(ErrorKind::EvaluateError(format!(
+            "Undefined variable: {}",
+            name
+        )))
+}
+
 fn number(value: &Value) -> Result<f64> {
     match *value {
         Value::Number(n) => Ok(n),
@@ -143,8 +451,32 @@ fn number(value: &Value) -> Result<f64> {
     }
 }
 
+/// Bitwise operators work on numbers with no fractional part that fit in
+/// the range a double can represent exactly.
+fn integer(value: &Value) -> Result<i64> {
+    const MAX_SAFE: f64 = 9007199254740991.0;
+    match *value {
+        Value::Number(n) if n.fract() == 0.0 && n.abs() <= MAX_SAFE => Ok(n as i64),
+        _ => Err(ErrorKind::EvaluateError(format!(
+            "Expected an integer, instead got: {}",
+            value
+        ))),
+    }
+}
+
+/// Floored modulo, so the result has the sign of the divisor and agrees
+/// with `~/`: `a == (a ~/ b) * b + a % b`.
+fn modulo(left: f64, right: f64) -> f64 {
+    let rem = left % right;
+    if rem != 0.0 && (rem < 0.0) != (right < 0.0) {
+        rem + right
+    } else {
+        rem
+    }
+}
+
 impl Evaluable for Grouping {
-    fn evaluate(&self, env: EnvRef) -> Result<Value> {
-        self.expr.evaluate(env)
+    fn evaluate(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Value> {
+        self.expr.evaluate(interpreter, env)
     }
 }
@@ -0,0 +1,53 @@
+//! Exceptions: values raised by `throw` and runtime errors, as seen by
+//! `catch` clauses.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::Value;
+use crate::class::{Class, Instance};
+use crate::errors::{ErrorKind, Result};
+use crate::interpreter::Interpreter;
+
+/// Starts throwing `value`, returning the error that carries it to the
+/// nearest handler. Errors have to be `Send`, so the value itself waits in
+/// the interpreter until a `catch` takes it.
+pub fn throw(interpreter: &mut Interpreter, value: Value) -> ErrorKind {
+    let message = format!("Uncaught exception: {}", describe(&value));
+    interpreter.thrown = Some(value);
+    ErrorKind::EvaluateError(message)
+}
+
+/// Turns an error into the value a `catch` clause binds: the thrown value,
+/// or an `Error` object for a runtime error. Other errors, like IO errors,
+/// can't be caught and are handed back.
+pub fn catch(interpreter: &mut Interpreter, e: ErrorKind) -> Result<Value> {
+    if let Some(value) = interpreter.thrown.take() {
+        return Ok(value);
+    }
+    match e {
+        ErrorKind::RuntimeError { tok, t, .. } => Ok(error(&t, tok.line)),
+        e => Err(e),
+    }
+}
+
+/// An `Error` instance with `message` and `line` fields.
+pub fn error(message: &str, line: usize) -> Value {
+    let class = Class::new("Error", None, HashMap::new());
+    let mut instance = Instance::new(Rc::new(class));
+    instance.set("message", Value::String(message.to_owned()));
+    instance.set("line", Value::Number(line as f64));
+    Value::Instance(Rc::new(RefCell::new(instance)))
+}
+
+/// How an uncaught exception is reported: by its message if it has one,
+/// like the objects runtime errors are caught as.
+fn describe(value: &Value) -> String {
+    if let Value::Instance(ref instance) = *value {
+        if let Ok(message) = Instance::get(instance, "message") {
+            return message.stringify();
+        }
+    }
+    value.stringify()
+}
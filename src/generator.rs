@@ -0,0 +1,484 @@
+//! Generators: the iterators returned by calls to `fun*` functions. A
+//! generator runs its body up to the next `yield` whenever it has to know
+//! whether there is another value, and keeps the body suspended there until
+//! it is needed again. Each engine suspends and resumes bodies its own way;
+//! this module holds what they share and the tree-walker's side.
+
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use crate::ast::{ForInStmt, FunctionDecl, Stmt, TryStmt, Value};
+use crate::callable;
+use crate::errors::{ErrorKind, Result};
+use crate::evaluable::Evaluable;
+use crate::exception;
+use crate::interpretable::{wrap, Completion, Interpretable};
+use crate::interpreter::{EnvRef, Interpreter};
+use crate::vm::GeneratorFrame;
+
+pub struct Generator {
+    /// The name of the generator function
+    pub name: String,
+    state: RefCell<State>,
+}
+
+enum State {
+    /// Waiting to run up to its next `yield`
+    Suspended(Frame),
+    Running,
+    /// Stopped at a `yield` whose value `next()` hasn't taken yet
+    Yielded(Value, Frame),
+    /// Returned, or raised an error
+    Done,
+}
+
+/// A suspended generator body, as saved by the engine running it.
+pub enum Frame {
+    Tree(TreeFrame),
+    Vm(GeneratorFrame),
+}
+
+impl Generator {
+    pub fn new(name: &str, frame: Frame) -> Generator {
+        Generator {
+            name: name.to_owned(),
+            state: RefCell::new(State::Suspended(frame)),
+        }
+    }
+
+    /// Takes the body to run if the generator doesn't know its next value
+    /// yet. The engine hands it back through `suspend` or `finish`.
+    pub fn resume(&self) -> Result<Option<Frame>> {
+        let mut state = self.state.borrow_mut();
+        match mem::replace(&mut *state, State::Running) {
+            State::Suspended(frame) => Ok(Some(frame)),
+            State::Running => Err(ErrorKind::EvaluateError(
+                "Generator is already running.".to_string(),
+            )),
+            other => {
+                *state = other;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Records that the body stopped at a `yield` of `value`.
+    pub fn suspend(&self, value: Value, frame: Frame) {
+        *self.state.borrow_mut() = State::Yielded(value, frame);
+    }
+
+    /// Records that the body returned or raised an error.
+    pub fn finish(&self) {
+        *self.state.borrow_mut() = State::Done;
+    }
+}
+
+// The suspended body usually refers back to the generator, so only print the name
+impl std::fmt::Debug for Generator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Generator({})", self.name)
+    }
+}
+
+/// Looks up the property `name` of a generator. Reading `done` only gives
+/// the right answer once the engine has resumed the generator, as
+/// `runs_on_get` asks it to.
+pub fn property(generator: &Rc<Generator>, name: &str) -> Option<Value> {
+    let receiver = Box::new(Value::Generator(generator.clone()));
+    match name {
+        "done" => Some(Value::Bool(matches!(
+            *generator.state.borrow(),
+            State::Done
+        ))),
+        "next" => Some(Value::BuiltinMethod(receiver, "next", &[], next)),
+        "iter" => Some(Value::BuiltinMethod(receiver, "iter", &[], iter)),
+        _ => None,
+    }
+}
+
+/// The generator that has to run before `name` can be read from `object`.
+pub fn runs_on_get<'a>(object: &'a Value, name: &str) -> Option<&'a Rc<Generator>> {
+    match *object {
+        Value::Generator(ref generator) if name == "done" => Some(generator),
+        _ => None,
+    }
+}
+
+/// The generator that has to run before `callee` can be called.
+pub fn runs_on_call(callee: &Value) -> Option<&Rc<Generator>> {
+    match *callee {
+        Value::BuiltinMethod(ref receiver, "next", _, _) => match **receiver {
+            Value::Generator(ref generator) => Some(generator),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn next(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let generator = match args[0] {
+        Value::Generator(ref generator) => generator,
+        _ => unreachable!("next() is only bound to generators"),
+    };
+    let mut state = generator.state.borrow_mut();
+    match mem::replace(&mut *state, State::Done) {
+        State::Yielded(value, frame) => {
+            *state = State::Suspended(frame);
+            Ok(value)
+        }
+        State::Done => Err(ErrorKind::EvaluateError(
+            "Iterator is exhausted.".to_string(),
+        )),
+        other => {
+            *state = other;
+            Err(ErrorKind::EvaluateError(
+                "Generator is already running.".to_string(),
+            ))
+        }
+    }
+}
+
+fn iter(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(args[0].clone())
+}
+
+/// A generator body suspended by the tree-walker.
+pub struct TreeFrame {
+    decl: Rc<FunctionDecl>,
+    /// The scope holding the arguments
+    env: EnvRef,
+    /// Which parameters got no arguments. Their defaults are evaluated when
+    /// the body starts.
+    missing: Vec<bool>,
+    /// Where the statements enclosing the last `yield` were, innermost
+    /// first. Empty before the body starts.
+    path: Vec<Cursor>,
+}
+
+impl TreeFrame {
+    pub fn new(decl: Rc<FunctionDecl>, env: EnvRef, missing: Vec<bool>) -> TreeFrame {
+        TreeFrame {
+            decl,
+            env,
+            missing,
+            path: vec![],
+        }
+    }
+}
+
+/// How far a statement enclosing a `yield` had got.
+enum Cursor {
+    /// At the statement `index` of a block, running in `env`
+    Block {
+        env: EnvRef,
+        index: usize,
+    },
+    /// In the branch of an `if` taken
+    If(bool),
+    /// In the body of a `while` loop
+    While,
+    /// In the body of a `for-in` loop, for the item bound in `scope`
+    ForIn {
+        iterator: Value,
+        scope: EnvRef,
+    },
+    Try(TryPart),
+}
+
+enum TryPart {
+    Body,
+    Catch,
+    /// In the `finally` block, with the outcome it runs after and the
+    /// exception set aside meanwhile
+    Finally(Result<Completion>, Option<Value>),
+}
+
+/// What running part of a generator body led to.
+enum Flow {
+    Done(Completion),
+    Yield(Value),
+}
+
+/// Runs a tree-walker generator up to its next `yield`, unless it already
+/// knows its next value. `line` is where the generator is being resumed.
+pub fn advance(interpreter: &mut Interpreter, generator: &Generator, line: usize) -> Result<()> {
+    let frame = match generator.resume()? {
+        Some(Frame::Tree(frame)) => frame,
+        Some(Frame::Vm(_)) => unreachable!("tree-walker resumed a VM generator"),
+        None => return Ok(()),
+    };
+    if let Err(e) = interpreter.push_frame(&generator.name, line) {
+        generator.finish();
+        return Err(e);
+    }
+    let resuming = !frame.path.is_empty();
+    let mut body = Body {
+        resuming,
+        path: frame.path,
+    };
+    let started = if resuming {
+        Ok(())
+    } else {
+        callable::bind_defaults(interpreter, &frame.decl, &frame.env, &frame.missing)
+    };
+    let res = started
+        .and_then(|_| body.resume_block(interpreter, &frame.decl.body, &frame.env, false))
+        .map_err(|e| interpreter.with_stack_trace(e));
+    interpreter.pop_frame();
+    match res {
+        Ok(Flow::Yield(value)) => {
+            let frame = TreeFrame {
+                path: body.path,
+                ..frame
+            };
+            generator.suspend(value, Frame::Tree(frame));
+            Ok(())
+        }
+        Ok(Flow::Done(_)) => {
+            generator.finish();
+            Ok(())
+        }
+        Err(e) => {
+            generator.finish();
+            Err(e)
+        }
+    }
+}
+
+/// Runs a generator body, or what's left of it. Statements that can't
+/// contain a `yield` are interpreted as usual; the others note how far they
+/// got on the way out of a `yield` and pick up from there when resumed.
+struct Body {
+    /// Set until the resumed `yield` is reached again
+    resuming: bool,
+    path: Vec<Cursor>,
+}
+
+impl Body {
+    /// The cursor of the statement being resumed, outermost first.
+    fn resume(&mut self) -> Option<Cursor> {
+        if self.resuming {
+            self.path.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Saves `cursor` if `flow` is leaving the statement at a `yield`.
+    fn save(&mut self, cursor: Cursor, flow: Result<Flow>) -> Result<Flow> {
+        if let Ok(Flow::Yield(_)) = flow {
+            self.path.push(cursor);
+        }
+        flow
+    }
+
+    /// Runs `stmts` in a new scope inside `env`, or directly in `env` if
+    /// `scoped` isn't set, or resumes them where they left off.
+    fn resume_block(
+        &mut self,
+        interpreter: &mut Interpreter,
+        stmts: &[Stmt],
+        env: &EnvRef,
+        scoped: bool,
+    ) -> Result<Flow> {
+        match self.resume() {
+            Some(Cursor::Block { env, index }) => self.block(interpreter, stmts, env, index),
+            None if scoped => self.block(interpreter, stmts, wrap(env), 0),
+            None => self.block(interpreter, stmts, env.clone(), 0),
+            Some(_) => unreachable!("generator resumed at the wrong statement"),
+        }
+    }
+
+    fn block(
+        &mut self,
+        interpreter: &mut Interpreter,
+        stmts: &[Stmt],
+        env: EnvRef,
+        start: usize,
+    ) -> Result<Flow> {
+        for (index, stmt) in stmts.iter().enumerate().skip(start) {
+            match self.stmt(interpreter, stmt, env.clone())? {
+                Flow::Done(Completion::Normal(_)) => {}
+                Flow::Yield(value) => {
+                    self.path.push(Cursor::Block { env, index });
+                    return Ok(Flow::Yield(value));
+                }
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Done(Completion::Normal(Value::Nil)))
+    }
+
+    fn stmt(&mut self, interpreter: &mut Interpreter, stmt: &Stmt, env: EnvRef) -> Result<Flow> {
+        match *stmt {
+            Stmt::Yield(_, ref value) => {
+                if self.resuming {
+                    self.resuming = false;
+                    return Ok(Flow::Done(Completion::Normal(Value::Nil)));
+                }
+                let value = match *value {
+                    Some(ref value) => value.evaluate(interpreter, env)?,
+                    None => Value::Nil,
+                };
+                Ok(Flow::Yield(value))
+            }
+            Stmt::Block(ref stmts) => self.resume_block(interpreter, stmts, &env, true),
+            Stmt::If(ref cond, ref if_stmt, ref else_stmt) => {
+                let branch = match self.resume() {
+                    Some(Cursor::If(branch)) => branch,
+                    None => cond.evaluate(interpreter, env.clone())?.is_truthy(),
+                    Some(_) => unreachable!("generator resumed at the wrong statement"),
+                };
+                let stmt = if branch {
+                    Some(&**if_stmt)
+                } else {
+                    else_stmt.as_deref()
+                };
+                match stmt {
+                    Some(stmt) => {
+                        let flow = self.stmt(interpreter, stmt, env);
+                        self.save(Cursor::If(branch), flow)
+                    }
+                    None => Ok(Flow::Done(Completion::Normal(Value::Nil))),
+                }
+            }
+            Stmt::While(ref cond, ref body, ref increment) => {
+                let mut resumed = match self.resume() {
+                    Some(Cursor::While) => true,
+                    None => false,
+                    Some(_) => unreachable!("generator resumed at the wrong statement"),
+                };
+                while resumed || cond.evaluate(interpreter, env.clone())?.is_truthy() {
+                    resumed = false;
+                    match self.stmt(interpreter, body, env.clone())? {
+                        Flow::Yield(value) => {
+                            self.path.push(Cursor::While);
+                            return Ok(Flow::Yield(value));
+                        }
+                        Flow::Done(Completion::Break) => break,
+                        flow @ Flow::Done(Completion::Return(_)) => return Ok(flow),
+                        Flow::Done(_) => {}
+                    }
+                    if let Some(ref increment) = *increment {
+                        increment.evaluate(interpreter, env.clone())?;
+                    }
+                }
+                Ok(Flow::Done(Completion::Normal(Value::Nil)))
+            }
+            Stmt::ForIn(ref f) => self.for_in(interpreter, f, env),
+            Stmt::Try(ref t) => self.try_stmt(interpreter, t, &env),
+            ref stmt => stmt.interpret(interpreter, env).map(Flow::Done),
+        }
+    }
+
+    fn for_in(
+        &mut self,
+        interpreter: &mut Interpreter,
+        f: &ForInStmt,
+        env: EnvRef,
+    ) -> Result<Flow> {
+        let (iterator, mut resumed) = match self.resume() {
+            Some(Cursor::ForIn { iterator, scope }) => (iterator, Some(scope)),
+            None => (f.iterator(interpreter, &env)?, None),
+            Some(_) => unreachable!("generator resumed at the wrong statement"),
+        };
+        loop {
+            let scope = match resumed.take() {
+                Some(scope) => scope,
+                None => match f.next_item(interpreter, &iterator)? {
+                    Some(item) => f.scope(&env, item),
+                    None => break,
+                },
+            };
+            match self.stmt(interpreter, &f.body, scope.clone())? {
+                Flow::Yield(value) => {
+                    self.path.push(Cursor::ForIn { iterator, scope });
+                    return Ok(Flow::Yield(value));
+                }
+                Flow::Done(Completion::Break) => break,
+                flow @ Flow::Done(Completion::Return(_)) => return Ok(flow),
+                Flow::Done(_) => {}
+            }
+        }
+        Ok(Flow::Done(Completion::Normal(Value::Nil)))
+    }
+
+    /// Runs a `try` statement the way `TryStmt::interpret` does, in parts
+    /// that can each be suspended.
+    fn try_stmt(
+        &mut self,
+        interpreter: &mut Interpreter,
+        t: &TryStmt,
+        env: &EnvRef,
+    ) -> Result<Flow> {
+        let part = match self.resume() {
+            Some(Cursor::Try(part)) => part,
+            None => TryPart::Body,
+            Some(_) => unreachable!("generator resumed at the wrong statement"),
+        };
+        let (res, thrown) = match part {
+            TryPart::Finally(res, thrown) => (res, thrown),
+            part => {
+                let res = match self.try_catch(interpreter, t, env, part) {
+                    Ok(Flow::Yield(value)) => return Ok(Flow::Yield(value)),
+                    Ok(Flow::Done(completion)) => Ok(completion),
+                    Err(e) => Err(e),
+                };
+                if t.finally.is_none() {
+                    return res.map(Flow::Done);
+                }
+                // Set aside an exception on its way out while the cleanup runs
+                (res, interpreter.thrown.take())
+            }
+        };
+        let finally = t.finally.as_ref().unwrap();
+        match self.resume_block(interpreter, finally, env, true)? {
+            Flow::Yield(value) => {
+                self.path.push(Cursor::Try(TryPart::Finally(res, thrown)));
+                Ok(Flow::Yield(value))
+            }
+            Flow::Done(Completion::Normal(_)) => {
+                interpreter.thrown = thrown;
+                res.map(Flow::Done)
+            }
+            // Jumping out of the `finally` block discards the exception
+            flow => Ok(flow),
+        }
+    }
+
+    /// Runs the body and `catch` clause of a `try` statement from `part`.
+    fn try_catch(
+        &mut self,
+        interpreter: &mut Interpreter,
+        t: &TryStmt,
+        env: &EnvRef,
+        part: TryPart,
+    ) -> Result<Flow> {
+        let res = match part {
+            TryPart::Body => {
+                let flow = self.resume_block(interpreter, &t.body, env, true);
+                self.save(Cursor::Try(TryPart::Body), flow)
+            }
+            _ => {
+                let handler = &t.catch.as_ref().unwrap().1;
+                let flow = self.resume_block(interpreter, handler, env, true);
+                return self.save(Cursor::Try(TryPart::Catch), flow);
+            }
+        };
+        match (res, &t.catch) {
+            (Err(e), Some((name, handler))) => {
+                match exception::catch(interpreter, e.at(&t.keyword)) {
+                    Ok(value) => {
+                        let handler_env = wrap(env);
+                        handler_env.borrow_mut().insert(&name.name.lexeme, value);
+                        let flow = self.block(interpreter, handler, handler_env, 0);
+                        self.save(Cursor::Try(TryPart::Catch), flow)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (res, _) => res,
+        }
+    }
+}
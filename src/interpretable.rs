@@ -1,74 +1,259 @@
 use crate::ast::*;
-use crate::errors::Result;
-use crate::evaluable::Evaluable;
-use crate::interpreter::{EnvRef, Environment};
+use crate::callable::{Callable, Function};
+use crate::class::Class;
+use crate::errors::{ErrorKind, Result};
+use crate::evaluable::{get_property, Evaluable};
+use crate::exception;
+use crate::generator;
+use crate::interpreter::{EnvRef, Environment, Interpreter};
+use crate::iterator::iter_method;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
+/// How a statement finished executing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Completion {
+    /// Ran to the end. Carries the value of the last expression statement
+    /// executed, which the REPL echoes.
+    Normal(Value),
+    /// Hit a `return` with the given value
+    Return(Value),
+    Break,
+    Continue,
+}
+
 pub trait Interpretable {
-    fn interpret(&self, env: EnvRef) -> Result<Value>;
+    fn interpret(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Completion>;
 }
 
 impl Interpretable for Stmt {
-    fn interpret(&self, env: EnvRef) -> Result<Value> {
+    fn interpret(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Completion> {
         match *self {
-            Stmt::Expr(ref expr) => expr.evaluate(env),
+            Stmt::Expr(ref expr) => expr.evaluate(interpreter, env).map(Completion::Normal),
             Stmt::Print(ref expr) => {
-                match expr.evaluate(env)? {
-                    Value::String(s) => println!("{}", s), // Print strings without double quotes
-                    x => println!("{}", x),
-                }
-                Ok(Value::Nil)
+                let value = expr.evaluate(interpreter, env)?;
+                interpreter.output().println(&value.stringify())?;
+                Ok(Completion::Normal(Value::Nil))
             }
-            Stmt::Decl(ref id, ref expr) => {
-                let value = expr.evaluate(env.clone())?;
-                RefCell::borrow_mut(&env).insert(&id.name.lexeme, value);
-                Ok(Value::Nil)
+            Stmt::Decl(ref id, ref expr, constant) => {
+                let value = expr.evaluate(interpreter, env.clone())?;
+                RefCell::borrow_mut(&env)
+                    .define(&id.name.lexeme, value, constant)
+                    .map_err(|e| e.at(&id.name))?;
+                Ok(Completion::Normal(Value::Nil))
             }
             Stmt::Block(ref stmts) => {
                 let new_env = Rc::new(RefCell::new(Environment::wrap(env.clone())));
-                let mut res = Value::Nil;
-                for stmt in stmts {
-                    res = stmt.interpret(new_env.clone())?;
-                    if let Value::Return(_) = res {
-                        return Ok(res);
-                    }
-                }
-                Ok(res)
+                execute_block(stmts, interpreter, new_env)
             }
             Stmt::If(ref cond, ref if_stmt, ref else_stmt) => {
-                let value = cond.evaluate(env.clone())?;
+                let value = cond.evaluate(interpreter, env.clone())?;
                 if value.is_truthy() {
-                    if_stmt.interpret(env.clone())
+                    if_stmt.interpret(interpreter, env.clone())
                 } else if let Some(ref else_stmt) = *else_stmt {
-                    else_stmt.interpret(env.clone())
+                    else_stmt.interpret(interpreter, env.clone())
                 } else {
-                    Ok(Value::Nil)
+                    Ok(Completion::Normal(Value::Nil))
                 }
             }
-            Stmt::While(ref cond, ref stmt) => {
-                let mut res = Value::Nil;
-                while cond.evaluate(env.clone())?.is_truthy() {
-                    res = stmt.interpret(env.clone())?;
-                    if let Value::Return(_) = res {
-                        return Ok(res);
+            Stmt::While(ref cond, ref stmt, ref increment) => {
+                let mut value = Value::Nil;
+                while cond.evaluate(interpreter, env.clone())?.is_truthy() {
+                    match stmt.interpret(interpreter, env.clone())? {
+                        Completion::Normal(v) => value = v,
+                        Completion::Continue => {}
+                        Completion::Break => break,
+                        res @ Completion::Return(_) => return Ok(res),
+                    }
+                    if let Some(ref increment) = *increment {
+                        increment.evaluate(interpreter, env.clone())?;
+                    }
+                }
+                Ok(Completion::Normal(value))
+            }
+            Stmt::ForIn(ref f) => f.interpret(interpreter, env),
+            Stmt::Break(_) => Ok(Completion::Break),
+            Stmt::Continue(_) => Ok(Completion::Continue),
+            Stmt::Func(ref decl) => {
+                let func = Function::new(decl.clone(), env.clone(), false);
+                RefCell::borrow_mut(&env)
+                    .define(&decl.name.lexeme, Value::Func(Rc::new(func)), false)
+                    .map_err(|e| e.at(&decl.name))?;
+                Ok(Completion::Normal(Value::Nil))
+            }
+            Stmt::Class(ref decl) => {
+                let superclass = match decl.superclass {
+                    Some(ref id) => {
+                        match Expr::Variable(id.clone()).evaluate(interpreter, env.clone())? {
+                            Value::Class(class) => Some(class),
+                            _ => {
+                                return Err(ErrorKind::EvaluateError(
+                                    "Superclass must be a class.".to_string(),
+                                )
+                                .at(&id.name))
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                // Methods of a subclass see `super` in an extra enclosing scope
+                let method_env = match superclass {
+                    Some(ref superclass) => {
+                        let mut super_env = Environment::wrap(env.clone());
+                        super_env.insert("super", Value::Class(superclass.clone()));
+                        Rc::new(RefCell::new(super_env))
                     }
+                    None => env.clone(),
+                };
+
+                let mut methods = HashMap::new();
+                for method in &decl.methods {
+                    let is_initializer = method.name.lexeme == "init";
+                    let func = Function::new(method.clone(), method_env.clone(), is_initializer);
+                    methods.insert(method.name.lexeme.clone(), Value::Func(Rc::new(func)));
+                }
+                let class = Class::new(&decl.name.lexeme, superclass, methods);
+                RefCell::borrow_mut(&env)
+                    .define(&decl.name.lexeme, Value::Class(Rc::new(class)), false)
+                    .map_err(|e| e.at(&decl.name))?;
+                Ok(Completion::Normal(Value::Nil))
+            }
+            Stmt::Throw(ref keyword, ref value) => {
+                let value = value.evaluate(interpreter, env)?;
+                Err(exception::throw(interpreter, value).at(keyword))
+            }
+            Stmt::Try(ref t) => t.interpret(interpreter, env),
+            Stmt::Import(ref import) => {
+                let module = interpreter
+                    .import(import.module_path())
+                    .map_err(|e| e.at(&import.path))?;
+                let mut env = RefCell::borrow_mut(&env);
+                for name in &import.names {
+                    let value = get_property(module.clone(), &name.name.lexeme)
+                        .map_err(|e| e.at(&name.name))?;
+                    env.define(&name.name.lexeme, value, false)
+                        .map_err(|e| e.at(&name.name))?;
+                }
+                if let Some(ref alias) = import.alias {
+                    env.define(&alias.name.lexeme, module, false)
+                        .map_err(|e| e.at(&alias.name))?;
                 }
-                Ok(res)
+                Ok(Completion::Normal(Value::Nil))
+            }
+            // Generator bodies are run by `generator::advance`, which handles
+            // every statement a `yield` can be nested in
+            Stmt::Yield(..) => unreachable!("yield outside of a generator body"),
+            Stmt::Return(_, ref expr) => {
+                let value = match *expr {
+                    Some(ref expr) => expr.evaluate(interpreter, env)?,
+                    None => Value::Nil,
+                };
+                Ok(Completion::Return(value))
+            }
+        }
+    }
+}
+
+impl ForInStmt {
+    /// Evaluates the iterable and gets an iterator from it.
+    pub(crate) fn iterator(&self, interpreter: &mut Interpreter, env: &EnvRef) -> Result<Value> {
+        let iterable = self.iterable.evaluate(interpreter, env.clone())?;
+        iter_method(iterable)
+            .and_then(|iter| iter.call(interpreter, &self.keyword, vec![], vec![]))
+            .map_err(|e| e.at(&self.keyword))
+    }
+
+    /// The next item from `iterator`, or `None` once it's done.
+    pub(crate) fn next_item(
+        &self,
+        interpreter: &mut Interpreter,
+        iterator: &Value,
+    ) -> Result<Option<Value>> {
+        if let Some(generator) = generator::runs_on_get(iterator, "done") {
+            generator::advance(interpreter, generator, self.keyword.line)
+                .map_err(|e| e.at(&self.keyword))?;
+        }
+        let at = |e: ErrorKind| e.at(&self.keyword);
+        if get_property(iterator.clone(), "done")
+            .map_err(at)?
+            .is_truthy()
+        {
+            return Ok(None);
+        }
+        get_property(iterator.clone(), "next")
+            .and_then(|next| next.call(interpreter, &self.keyword, vec![], vec![]))
+            .map(Some)
+            .map_err(at)
+    }
+
+    /// A scope for one iteration with the loop variable bound to `item`.
+    pub(crate) fn scope(&self, env: &EnvRef, item: Value) -> EnvRef {
+        let scope = wrap(env);
+        scope.borrow_mut().insert(&self.name.name.lexeme, item);
+        scope
+    }
+}
+
+impl Interpretable for ForInStmt {
+    fn interpret(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Completion> {
+        let iterator = self.iterator(interpreter, &env)?;
+        let mut value = Value::Nil;
+        while let Some(item) = self.next_item(interpreter, &iterator)? {
+            match self.body.interpret(interpreter, self.scope(&env, item))? {
+                Completion::Normal(v) => value = v,
+                Completion::Continue => {}
+                Completion::Break => break,
+                res @ Completion::Return(_) => return Ok(res),
+            }
+        }
+        Ok(Completion::Normal(value))
+    }
+}
+
+impl Interpretable for TryStmt {
+    fn interpret(&self, interpreter: &mut Interpreter, env: EnvRef) -> Result<Completion> {
+        let mut res = execute_block(&self.body, interpreter, wrap(&env));
+        if let Some((ref name, ref handler)) = self.catch {
+            if let Err(e) = res {
+                res = exception::catch(interpreter, e.at(&self.keyword)).and_then(|value| {
+                    let handler_env = wrap(&env);
+                    handler_env.borrow_mut().insert(&name.name.lexeme, value);
+                    execute_block(handler, interpreter, handler_env)
+                });
             }
-            Stmt::Func(ref name, ref params, ref body) => {
-                RefCell::borrow_mut(&env).insert(
-                    &name.lexeme,
-                    Value::Func(
-                        name.to_owned(),
-                        env.clone(),
-                        params.to_owned(),
-                        body.to_owned(),
-                    ),
-                );
-                Ok(Value::Nil)
+        }
+        if let Some(ref finally) = self.finally {
+            // Set aside an exception on its way out while the cleanup runs
+            let thrown = interpreter.thrown.take();
+            match execute_block(finally, interpreter, wrap(&env))? {
+                Completion::Normal(_) => interpreter.thrown = thrown,
+                // Jumping out of the `finally` block discards the exception
+                completion => return Ok(completion),
             }
-            Stmt::Return(ref expr) => Ok(Value::Return(Box::new(expr.evaluate(env)?))),
+        }
+        res
+    }
+}
+
+pub(crate) fn wrap(env: &EnvRef) -> EnvRef {
+    Rc::new(RefCell::new(Environment::wrap(env.clone())))
+}
+
+/// Runs `stmts` in `env`, stopping early if one of them returns or jumps out
+/// of a loop.
+pub fn execute_block(
+    stmts: &[Stmt],
+    interpreter: &mut Interpreter,
+    env: EnvRef,
+) -> Result<Completion> {
+    let mut res = Completion::Normal(Value::Nil);
+    for stmt in stmts {
+        res = stmt.interpret(interpreter, env.clone())?;
+        if !matches!(res, Completion::Normal(_)) {
+            return Ok(res);
         }
     }
+    Ok(res)
 }
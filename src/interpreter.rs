@@ -1,34 +1,130 @@
 use std::cell::RefCell;
 use std::fmt;
-use std::fs::File;
-use std::path::Path;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 
 use std::io::{self, BufRead, Read, Write};
+use std::iter;
+use std::mem;
 use std::rc::Rc;
+use std::str::FromStr;
 
 use anyhow::Result;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::ast::*;
 use crate::builtins::*;
-use crate::interpretable::Interpretable;
+use crate::errors::{self, ErrorKind, TraceFrame};
+use crate::interpretable::{Completion, Interpretable};
+use crate::module::{self, Module, Scope};
+use crate::output::Output;
 use crate::parser::Parser;
+use crate::resolver::Resolver;
 use crate::scanner::Scanner;
+use crate::vm::compiler::Compiler;
+use crate::vm::Vm;
+
+/// Maximum number of nested Lox calls before reporting a stack overflow
+pub(crate) const MAX_FRAMES: usize = 1024;
+
+/// How an interpreter executes resolved code. Both engines produce the same
+/// output and errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Engine {
+    /// Walks the syntax tree directly
+    #[default]
+    TreeWalker,
+    /// Compiles to bytecode and runs it on a stack machine
+    Vm,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Engine, String> {
+        match s {
+            "tree" => Ok(Engine::TreeWalker),
+            "vm" => Ok(Engine::Vm),
+            _ => Err(format!("Unknown engine '{}', expected 'tree' or 'vm'", s)),
+        }
+    }
+}
+
+/// A Lox function call that hasn't returned yet.
+struct CallFrame {
+    function: String,
+    /// Line of the call in the calling frame
+    call_line: usize,
+}
 
 pub struct Interpreter {
     env: EnvRef,
+    output: Output,
+    frames: Vec<CallFrame>,
+    engine: Engine,
+    vm: Vm,
+    /// Objects of the optional chains being evaluated, innermost last
+    pub(crate) chained: Vec<Value>,
+    /// The value being thrown, until a `catch` takes it
+    pub(crate) thrown: Option<Value>,
+    /// Directories searched for modules not found next to the importing file
+    search_path: Vec<PathBuf>,
+    /// Modules that have been run, by the engine that ran them and their file
+    modules: HashMap<(Engine, PathBuf), Value>,
+    /// The files being run, the one whose imports are being resolved last
+    loading: Vec<PathBuf>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
 }
 
 impl Interpreter {
+    /// Creates an interpreter that prints to stdout.
     pub fn new() -> Interpreter {
-        let mut env = Environment::new();
-        env.insert("clock", Value::BuiltinFunc("clock".to_string(), 0, clock));
+        Interpreter::with_output(Output::stdout())
+    }
+
+    /// Creates an interpreter that sends everything the program prints to `output`.
+    pub fn with_output<O: Into<Output>>(output: O) -> Interpreter {
         Interpreter {
-            env: Rc::new(RefCell::new(env)),
+            env: global_env(),
+            output: output.into(),
+            frames: vec![],
+            engine: Engine::default(),
+            vm: Vm::new(),
+            chained: vec![],
+            thrown: None,
+            search_path: vec![],
+            modules: HashMap::new(),
+            loading: vec![],
         }
     }
 
+    /// Switches engines. Each engine keeps its own globals, so definitions
+    /// from earlier runs are only visible to the engine that ran them.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    /// Sets the directories searched, in order, for modules that aren't
+    /// found relative to the importing file.
+    pub fn set_search_path(&mut self, dirs: Vec<PathBuf>) {
+        self.search_path = dirs;
+    }
+
+    pub fn set_output<O: Into<Output>>(&mut self, output: O) {
+        self.output = output.into();
+    }
+
+    /// The sink used by `print` and by builtins that produce output.
+    pub fn output(&mut self) -> &mut Output {
+        &mut self.output
+    }
+
     pub fn run_prompt(&mut self) -> io::Result<()> {
         let stdin = io::stdin();
         print!("> ");
@@ -45,30 +141,163 @@ impl Interpreter {
         Ok(())
     }
 
+    /// Runs the script at `path`, which its imports are resolved relative to.
     pub fn run_path<P: AsRef<Path>>(&mut self, path: P) -> Result<Value> {
         let mut s = String::new();
-        let mut file = File::open(path)?;
+        let mut file = File::open(&path)?;
         file.read_to_string(&mut s)?;
-        self.run(&s)
+        self.loading.push(path.as_ref().canonicalize()?);
+        let res = self.run(&s);
+        self.loading.pop();
+        res
     }
 
     pub fn run(&mut self, code: &str) -> Result<Value> {
-        let scanner = Scanner::new(code);
-        let stmts = scanner
-            .scan_tokens()
-            .and_then(|tokens| Parser::new(tokens).parse())?;
+        let stmts = parse(code)?;
+        let res = if self.engine == Engine::Vm {
+            let script = Compiler::new().compile(&stmts);
+            let mut vm = mem::take(&mut self.vm);
+            let res = vm.run(self, script);
+            self.vm = vm;
+            res
+        } else {
+            let env = self.env.clone();
+            self.walk(&stmts, env)
+        };
+        // An exception nobody caught mustn't leak into the next run
+        self.thrown = None;
+        Ok(res?)
+    }
+
+    /// The namespace of the module at `path`, running the module first if
+    /// this engine hasn't imported it before.
+    pub(crate) fn import(&mut self, path: &str) -> errors::Result<Value> {
+        let file = module::find(
+            path,
+            self.loading.last().map(PathBuf::as_path),
+            &self.search_path,
+        )?;
+        let key = (self.engine, file);
+        if let Some(module) = self.modules.get(&key) {
+            return Ok(module.clone());
+        }
+        let file = key.1.clone();
+        if let Some(start) = self.loading.iter().position(|f| *f == file) {
+            let cycle: Vec<_> = self.loading[start..]
+                .iter()
+                .chain(iter::once(&file))
+                .map(|f| f.file_name().unwrap_or_default().to_string_lossy())
+                .collect();
+            return Err(ErrorKind::EvaluateError(format!(
+                "Import cycle: {}.",
+                cycle.join(" -> ")
+            )));
+        }
+        let stmts = fs::read_to_string(&file)
+            .map_err(ErrorKind::from)
+            .and_then(|code| parse(&code))
+            .map_err(|e| {
+                ErrorKind::EvaluateError(format!("Can't load module \"{}\": {}", path, e))
+            })?;
+
+        self.loading.push(file);
+        let scope = if self.engine == Engine::Vm {
+            let mut vm = Vm::new();
+            let script = Compiler::new().compile(&stmts);
+            vm.run(self, script).map(|_| Scope::Globals(vm.globals()))
+        } else {
+            let env = global_env();
+            self.walk(&stmts, env.clone()).map(|_| Scope::Env(env))
+        };
+        self.loading.pop();
 
+        let module = Value::Module(Rc::new(Module::new(path, &stmts, scope?)));
+        self.modules.insert(key, module.clone());
+        Ok(module)
+    }
+
+    fn walk(&mut self, stmts: &[Stmt], env: EnvRef) -> errors::Result<Value> {
         let mut last_val = Value::Nil;
         for stmt in stmts {
-            last_val = stmt.interpret(self.env.clone())?;
+            let env = env.clone();
+            last_val = match stmt
+                .interpret(self, env)
+                .map_err(|e| self.with_stack_trace(e))?
+            {
+                Completion::Normal(value) => value,
+                // The resolver and parser reject these outside of functions
+                // and loops
+                Completion::Return(_) | Completion::Break | Completion::Continue => {
+                    unreachable!("control flow escaped to the top level")
+                }
+            };
         }
         Ok(last_val)
     }
+
+    pub(crate) fn push_frame(&mut self, function: &str, call_line: usize) -> errors::Result<()> {
+        if self.frames.len() >= MAX_FRAMES {
+            return Err(ErrorKind::EvaluateError("Stack overflow.".to_string()));
+        }
+        self.frames.push(CallFrame {
+            function: function.to_owned(),
+            call_line,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn pop_frame(&mut self) {
+        self.frames.pop();
+    }
+
+    /// Records the active call frames in a runtime error that doesn't have a
+    /// stack trace yet. Must be called before the innermost frame is popped.
+    pub(crate) fn with_stack_trace(&self, e: ErrorKind) -> ErrorKind {
+        match e {
+            ErrorKind::RuntimeError { tok, t, trace } if trace.is_empty() => {
+                let mut trace = vec![];
+                let mut line = tok.line;
+                for frame in self.frames.iter().rev() {
+                    trace.push(TraceFrame {
+                        line,
+                        function: Some(frame.function.clone()),
+                    });
+                    line = frame.call_line;
+                }
+                trace.push(TraceFrame {
+                    line,
+                    function: None,
+                });
+                ErrorKind::RuntimeError { tok, t, trace }
+            }
+            e => e,
+        }
+    }
+}
+
+/// Scans, parses and resolves a script or module.
+fn parse(code: &str) -> errors::Result<Vec<Stmt>> {
+    let stmts = Scanner::new(code)
+        .scan_tokens()
+        .and_then(|tokens| Parser::new(tokens).parse())?;
+    Resolver::new().resolve(&stmts)?;
+    Ok(stmts)
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A fresh global scope holding only the builtins.
+fn global_env() -> EnvRef {
+    let mut env = Environment::new();
+    for (name, value) in globals() {
+        env.insert(name, value);
+    }
+    Rc::new(RefCell::new(env))
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Environment {
     map: HashMap<String, ValRef>,
+    /// The variables declared with `const`
+    constants: HashSet<String>,
     parent: Option<Rc<RefCell<Environment>>>,
 }
 
@@ -78,6 +307,7 @@ impl Environment {
     pub fn new() -> Environment {
         Environment {
             map: HashMap::new(),
+            constants: HashSet::new(),
             parent: None,
         }
     }
@@ -85,29 +315,65 @@ impl Environment {
     pub fn wrap(parent: EnvRef) -> Environment {
         Environment {
             map: HashMap::new(),
+            constants: HashSet::new(),
             parent: Some(parent),
         }
     }
 
-    pub fn get(&self, s: &str) -> Option<ValRef> {
-        if let Some(v) = self.map.get(s) {
-            return Some(v.clone());
-        }
-        if let Some(ref parent) = self.parent {
-            return parent.borrow().get(s);
+    /// Looks `s` up `depth` scopes out from this one, or in the outermost
+    /// (global) scope if `depth` is `None`.
+    pub fn get_at(&self, depth: Option<usize>, s: &str) -> Option<ValRef> {
+        match (depth, &self.parent) {
+            (Some(0), _) | (None, None) => self.map.get(s).cloned(),
+            (Some(depth), Some(parent)) => parent.borrow().get_at(Some(depth - 1), s),
+            (None, Some(parent)) => parent.borrow().get_at(None, s),
+            (Some(_), None) => None,
         }
-        None
     }
 
     pub fn insert(&mut self, s: &str, v: Value) {
         self.map.insert(s.to_owned(), Rc::new(RefCell::new(v)));
     }
 
-    pub fn update(&mut self, s: &str, v: Value) -> Option<Value> {
-        self.map.get_mut(s).map(|val| {
-            *val = Rc::new(RefCell::new(v.clone()));
-            v
-        })
+    /// Declares a variable in this scope. The resolver rejects redeclaring
+    /// a constant within one script, so this only fails for a global
+    /// constant defined by an earlier run, like a previous line in the REPL.
+    pub fn define(&mut self, s: &str, v: Value, constant: bool) -> errors::Result<()> {
+        if self.constants.contains(s) {
+            return Err(ErrorKind::EvaluateError(format!(
+                "Can't redeclare constant '{}'.",
+                s
+            )));
+        }
+        if constant {
+            self.constants.insert(s.to_owned());
+        }
+        self.insert(s, v);
+        Ok(())
+    }
+
+    fn is_constant_at(&self, depth: Option<usize>, s: &str) -> bool {
+        match (depth, &self.parent) {
+            (Some(0), _) | (None, None) => self.constants.contains(s),
+            (Some(depth), Some(parent)) => parent.borrow().is_constant_at(Some(depth - 1), s),
+            (None, Some(parent)) => parent.borrow().is_constant_at(None, s),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Overwrites an existing variable in place so closures sharing it see the change.
+    pub fn assign_at(&self, depth: Option<usize>, s: &str, v: Value) -> errors::Result<Value> {
+        if self.is_constant_at(depth, s) {
+            return Err(ErrorKind::EvaluateError(format!(
+                "Can't assign to constant '{}'.",
+                s
+            )));
+        }
+        let val = self
+            .get_at(depth, s)
+            .ok_or_else(|| ErrorKind::EvaluateError(format!("Undefined variable: {}", s)))?;
+        *val.borrow_mut() = v.clone();
+        Ok(v)
     }
 }
 
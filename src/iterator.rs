@@ -0,0 +1,122 @@
+//! Iteration for `for (item in iterable)` loops. A loop calls the iterable's
+//! `iter()` method and then calls `next()` on the result until its `done`
+//! property is truthy. Lists, maps, strings and ranges get builtin
+//! iterators following the same protocol as user classes.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::ast::{ListRef, Value};
+use crate::errors::{ErrorKind, Result};
+use crate::evaluable::get_property;
+use crate::generator;
+use crate::interpreter::Interpreter;
+
+pub type IterRef = Rc<RefCell<Iter>>;
+
+/// A builtin iterator, which is its own iterable.
+#[derive(Debug)]
+pub enum Iter {
+    /// The items of a list, including ones added during the loop
+    List(ListRef, usize),
+    /// Map keys or string characters, as they were when the loop started
+    Items(Vec<Value>, usize),
+    /// Numbers from `next` up to but excluding `end`
+    Range { next: f64, end: f64 },
+}
+
+impl Iter {
+    fn done(&self) -> bool {
+        match *self {
+            Iter::List(ref list, i) => i >= list.borrow().len(),
+            Iter::Items(ref items, i) => i >= items.len(),
+            Iter::Range { next, end } => next >= end,
+        }
+    }
+
+    fn next(&mut self) -> Option<Value> {
+        match *self {
+            Iter::List(ref list, ref mut i) => {
+                let item = list.borrow().get(*i).cloned();
+                *i += 1;
+                item
+            }
+            Iter::Items(ref items, ref mut i) => {
+                let item = items.get(*i).cloned();
+                *i += 1;
+                item
+            }
+            Iter::Range { ref mut next, end } => {
+                let item = (*next < end).then_some(Value::Number(*next));
+                *next += 1.0;
+                item
+            }
+        }
+    }
+}
+
+fn new(iter: Iter) -> Value {
+    Value::Iterator(Rc::new(RefCell::new(iter)))
+}
+
+/// The `iter()` method a `for-in` loop calls to start iterating over `value`.
+pub fn iter_method(value: Value) -> Result<Value> {
+    match value {
+        Value::Instance(_) => get_property(value, "iter"),
+        Value::List(_) | Value::Map(_) | Value::String(_) | Value::Iterator(_) => {
+            Ok(Value::BuiltinMethod(Box::new(value), "iter", &[], iter))
+        }
+        Value::Generator(ref generator) => Ok(generator::property(generator, "iter").unwrap()),
+        value => Err(ErrorKind::EvaluateError(format!(
+            "Can only iterate over lists, maps, strings, ranges, generators and objects with an iter() method, instead got: {}",
+            value
+        ))),
+    }
+}
+
+/// Looks up the property `name` of a builtin iterator.
+pub fn property(it: &IterRef, name: &str) -> Option<Value> {
+    let receiver = Box::new(Value::Iterator(it.clone()));
+    match name {
+        "done" => Some(Value::Bool(it.borrow().done())),
+        "next" => Some(Value::BuiltinMethod(receiver, "next", &[], next)),
+        "iter" => Some(Value::BuiltinMethod(receiver, "iter", &[], iter)),
+        _ => None,
+    }
+}
+
+/// `range(start, end)`: the numbers from `start` up to but excluding `end`.
+pub fn range(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    match (&args[0], &args[1]) {
+        (&Value::Number(next), &Value::Number(end)) => Ok(new(Iter::Range { next, end })),
+        _ => Err(ErrorKind::EvaluateError(
+            "Range bounds must be numbers.".to_string(),
+        )),
+    }
+}
+
+fn iter(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(match args[0] {
+        Value::Iterator(ref it) => Value::Iterator(it.clone()),
+        Value::List(ref list) => new(Iter::List(list.clone(), 0)),
+        Value::Map(ref map) => {
+            let keys = map.borrow().iter().map(|(key, _)| key.to_value()).collect();
+            new(Iter::Items(keys, 0))
+        }
+        Value::String(ref s) => {
+            let chars = s.chars().map(|c| Value::String(c.to_string())).collect();
+            new(Iter::Items(chars, 0))
+        }
+        _ => unreachable!("iter() is only bound to iterable values"),
+    })
+}
+
+fn next(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    match args[0] {
+        Value::Iterator(ref it) => it
+            .borrow_mut()
+            .next()
+            .ok_or_else(|| ErrorKind::EvaluateError("Iterator is exhausted.".to_string())),
+        _ => unreachable!("next() is only bound to iterators"),
+    }
+}
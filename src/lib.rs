@@ -0,0 +1,30 @@
+#[macro_use]
+extern crate lazy_static;
+
+pub mod interpreter;
+pub use interpreter::{Engine, Interpreter};
+
+pub mod output;
+pub use output::{Output, SharedBuffer};
+
+pub mod errors;
+
+#[cfg(test)]
+mod tests;
+
+pub mod ast;
+pub mod builtins;
+pub mod callable;
+pub mod class;
+pub mod evaluable;
+pub mod exception;
+pub mod generator;
+pub mod interpretable;
+pub mod iterator;
+pub mod list;
+pub mod map;
+pub mod module;
+pub mod parser;
+pub mod resolver;
+pub mod scanner;
+pub mod vm;
@@ -0,0 +1,115 @@
+//! Lox lists and their built-in methods.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::ast::{BuitinFunc, ListRef, Params, Value};
+use crate::errors::{ErrorKind, Result};
+use crate::interpreter::Interpreter;
+
+/// Name, parameters and implementation of every list method. Implementations get
+/// the list itself as their first argument.
+const METHODS: &[(&str, Params, BuitinFunc)] = &[
+    ("len", &[], len),
+    ("push", &["item"], push),
+    ("pop", &[], pop),
+    ("insert", &["index", "item"], insert),
+    ("remove", &["index"], remove),
+    ("slice", &["start", "end"], slice),
+    ("reverse", &[], reverse),
+];
+
+pub fn new(items: Vec<Value>) -> Value {
+    Value::List(Rc::new(RefCell::new(items)))
+}
+
+/// Looks up the method `name` bound to `list`.
+pub fn method(list: &ListRef, name: &str) -> Option<Value> {
+    METHODS
+        .iter()
+        .find(|&&(method, _, _)| method == name)
+        .map(|&(name, params, func)| {
+            Value::BuiltinMethod(Box::new(Value::List(list.clone())), name, params, func)
+        })
+}
+
+/// Converts `index` into a position in a list of length `len`. Positions up
+/// to and including `len` are accepted if `allow_end` is set.
+pub fn index(index: &Value, len: usize, allow_end: bool) -> Result<usize> {
+    let n = match *index {
+        Value::Number(n) if n.fract() == 0.0 => n,
+        _ => {
+            return Err(ErrorKind::EvaluateError(format!(
+                "List index must be an integer, got {}.",
+                index
+            )))
+        }
+    };
+    let limit = if allow_end { len + 1 } else { len };
+    if n < 0.0 || n >= limit as f64 {
+        return Err(ErrorKind::EvaluateError(format!(
+            "List index {} is out of bounds for length {}.",
+            n, len
+        )));
+    }
+    Ok(n as usize)
+}
+
+fn receiver(args: &[Value]) -> ListRef {
+    match args[0] {
+        Value::List(ref list) => list.clone(),
+        _ => unreachable!("list methods are only bound to lists"),
+    }
+}
+
+fn len(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(Value::Number(receiver(&args).borrow().len() as f64))
+}
+
+fn push(_interpreter: &mut Interpreter, mut args: Vec<Value>) -> Result<Value> {
+    let value = args.pop().unwrap();
+    receiver(&args).borrow_mut().push(value);
+    Ok(Value::Nil)
+}
+
+fn pop(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    receiver(&args)
+        .borrow_mut()
+        .pop()
+        .ok_or_else(|| ErrorKind::EvaluateError("Can't pop from an empty list.".to_string()))
+}
+
+fn insert(_interpreter: &mut Interpreter, mut args: Vec<Value>) -> Result<Value> {
+    let value = args.pop().unwrap();
+    let list = receiver(&args);
+    let i = index(&args[1], list.borrow().len(), true)?;
+    list.borrow_mut().insert(i, value);
+    Ok(Value::Nil)
+}
+
+fn remove(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let list = receiver(&args);
+    let i = index(&args[1], list.borrow().len(), false)?;
+    let value = list.borrow_mut().remove(i);
+    Ok(value)
+}
+
+/// Copies the items from `start` up to but not including `end`.
+fn slice(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let list = receiver(&args);
+    let list = list.borrow();
+    let start = index(&args[1], list.len(), true)?;
+    let end = index(&args[2], list.len(), true)?;
+    if start > end {
+        return Err(ErrorKind::EvaluateError(format!(
+            "Slice start {} is after its end {}.",
+            start, end
+        )));
+    }
+    Ok(new(list[start..end].to_vec()))
+}
+
+fn reverse(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    receiver(&args).borrow_mut().reverse();
+    Ok(Value::Nil)
+}
@@ -1,29 +1,61 @@
-#[macro_use]
-extern crate lazy_static;
 use std::env;
+use std::process;
+use std::thread;
 
-mod interpreter;
-use interpreter::Interpreter;
+use rlox::errors::ErrorKind;
+use rlox::{Engine, Interpreter};
 
-mod errors;
+/// Deeply recursive Lox code recurses just as deeply in the interpreter, so
+/// give it enough stack to reach its own frame limit.
+const STACK_SIZE: usize = 64 * 1024 * 1024;
 
-#[cfg(test)]
-mod tests;
+fn main() {
+    let child = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .unwrap();
+    process::exit(child.join().unwrap());
+}
 
-mod ast;
-mod builtins;
-mod callable;
-mod evaluable;
-mod interpretable;
-mod parser;
-mod scanner;
+fn run() -> i32 {
+    let mut engine = Engine::default();
+    let mut scripts = vec![];
+    for arg in env::args().skip(1) {
+        match arg.strip_prefix("--engine=") {
+            Some(name) => match name.parse() {
+                Ok(e) => engine = e,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 64;
+                }
+            },
+            None => scripts.push(arg),
+        }
+    }
 
-fn main() {
-    if env::args().len() > 2 {
-        println!("Usage: rlox [script]");
-    } else if let Some(script) = env::args().nth(1) {
-        Interpreter::new().run_path(script).unwrap();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_engine(engine);
+    // Like PATH, a list of directories to look for imported modules in
+    if let Some(dirs) = env::var_os("RLOX_PATH") {
+        interpreter.set_search_path(env::split_paths(&dirs).collect());
+    }
+    if scripts.len() > 1 {
+        println!("Usage: rlox [--engine=tree|vm] [script]");
+        64
+    } else if let Some(script) = scripts.pop() {
+        match interpreter.run_path(script) {
+            Ok(_) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                match e.downcast_ref::<ErrorKind>() {
+                    Some(ErrorKind::RuntimeError { .. }) | Some(ErrorKind::EvaluateError(_)) => 70,
+                    Some(ErrorKind::IO(_)) | None => 74,
+                    Some(_) => 65,
+                }
+            }
+        }
     } else {
-        Interpreter::new().run_prompt().unwrap();
+        interpreter.run_prompt().unwrap();
+        0
     }
 }
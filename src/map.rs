@@ -0,0 +1,154 @@
+//! Lox maps and their built-in methods.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::{BuitinFunc, Params, Value};
+use crate::errors::{ErrorKind, Result};
+use crate::interpreter::Interpreter;
+use crate::list;
+
+pub type MapRef = Rc<RefCell<Map>>;
+
+/// The values that can be used as map keys. Numbers are stored by their bits
+/// with `-0` folded into `0`, so keys that compare equal hash equally. NaN
+/// isn't equal to itself, so it can't be a key at all.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Bool(bool),
+    Number(u64),
+    String(String),
+}
+
+impl Key {
+    pub fn new(value: &Value) -> Result<Key> {
+        match *value {
+            Value::Bool(b) => Ok(Key::Bool(b)),
+            Value::Number(n) if n.is_nan() => Err(ErrorKind::EvaluateError(
+                "NaN can't be used as a map key.".to_string(),
+            )),
+            Value::Number(n) => {
+                let n = if n == 0.0 { 0.0 } else { n };
+                Ok(Key::Number(n.to_bits()))
+            }
+            Value::String(ref s) => Ok(Key::String(s.clone())),
+            _ => Err(ErrorKind::EvaluateError(format!(
+                "{} can't be used as a map key; only strings, numbers and booleans can.",
+                value
+            ))),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match *self {
+            Key::Bool(b) => Value::Bool(b),
+            Key::Number(bits) => Value::Number(f64::from_bits(bits)),
+            Key::String(ref s) => Value::String(s.clone()),
+        }
+    }
+}
+
+/// A map that remembers insertion order, so printing and iterating over it
+/// is deterministic.
+#[derive(Debug, Default)]
+pub struct Map {
+    entries: HashMap<Key, Value>,
+    keys: Vec<Key>,
+}
+
+impl Map {
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: Key, value: Value) {
+        if self.entries.insert(key.clone(), value).is_none() {
+            self.keys.push(key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Option<Value> {
+        let value = self.entries.remove(key)?;
+        self.keys.retain(|k| k != key);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Entries in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Value)> {
+        self.keys.iter().map(move |key| (key, &self.entries[key]))
+    }
+}
+
+const METHODS: &[(&str, Params, BuitinFunc)] = &[
+    ("has", &["key"], has),
+    ("delete", &["key"], delete),
+    ("keys", &[], keys),
+    ("values", &[], values),
+    ("size", &[], size),
+];
+
+pub fn new(map: Map) -> Value {
+    Value::Map(Rc::new(RefCell::new(map)))
+}
+
+/// Looks up the method `name` bound to `map`.
+pub fn method(map: &MapRef, name: &str) -> Option<Value> {
+    METHODS
+        .iter()
+        .find(|&&(method, _, _)| method == name)
+        .map(|&(name, params, func)| {
+            Value::BuiltinMethod(Box::new(Value::Map(map.clone())), name, params, func)
+        })
+}
+
+pub fn missing_key(key: &Key) -> ErrorKind {
+    ErrorKind::EvaluateError(format!("Map has no key {}.", key.to_value()))
+}
+
+fn receiver(args: &[Value]) -> MapRef {
+    match args[0] {
+        Value::Map(ref map) => map.clone(),
+        _ => unreachable!("map methods are only bound to maps"),
+    }
+}
+
+fn has(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let key = Key::new(&args[1])?;
+    Ok(Value::Bool(receiver(&args).borrow().get(&key).is_some()))
+}
+
+/// Removes a key, returning its value.
+fn delete(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let key = Key::new(&args[1])?;
+    let value = receiver(&args).borrow_mut().remove(&key);
+    value.ok_or_else(|| missing_key(&key))
+}
+
+fn keys(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let map = receiver(&args);
+    let keys = map.borrow().iter().map(|(key, _)| key.to_value()).collect();
+    Ok(list::new(keys))
+}
+
+fn values(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    let map = receiver(&args);
+    let values = map
+        .borrow()
+        .iter()
+        .map(|(_, value)| value.clone())
+        .collect();
+    Ok(list::new(values))
+}
+
+fn size(_interpreter: &mut Interpreter, args: Vec<Value>) -> Result<Value> {
+    Ok(Value::Number(receiver(&args).borrow().len() as f64))
+}
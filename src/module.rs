@@ -0,0 +1,97 @@
+//! Modules: Lox files loaded by `import` statements, whose top-level
+//! bindings are reached through a namespace value.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{Stmt, Value};
+use crate::errors::{ErrorKind, Result};
+use crate::interpreter::EnvRef;
+use crate::vm::object::Globals;
+
+/// A module that has been run, holding on to its globals so later changes
+/// to them are visible through the namespace.
+pub struct Module {
+    /// The path the module was first imported by
+    pub name: String,
+    scope: Scope,
+    /// The names the module declares at the top level. The builtins share
+    /// its global scope but aren't part of its namespace.
+    bindings: HashSet<String>,
+}
+
+/// Where a module's top-level bindings live, depending on the engine that
+/// ran it.
+pub enum Scope {
+    Env(EnvRef),
+    Globals(Globals),
+}
+
+impl Module {
+    /// A module that ran `stmts` and left its globals in `scope`.
+    pub fn new(name: &str, stmts: &[Stmt], scope: Scope) -> Module {
+        let bindings = stmts
+            .iter()
+            .flat_map(|stmt| match *stmt {
+                Stmt::Decl(ref id, _, _) => vec![&id.name.lexeme],
+                Stmt::Func(ref decl) => vec![&decl.name.lexeme],
+                Stmt::Class(ref decl) => vec![&decl.name.lexeme],
+                Stmt::Import(ref import) => import
+                    .names
+                    .iter()
+                    .chain(&import.alias)
+                    .map(|id| &id.name.lexeme)
+                    .collect(),
+                _ => vec![],
+            })
+            .cloned()
+            .collect();
+        Module {
+            name: name.to_owned(),
+            scope,
+            bindings,
+        }
+    }
+
+    /// The current value of the top-level binding `name`.
+    pub fn get(&self, name: &str) -> Option<Value> {
+        if !self.bindings.contains(name) {
+            return None;
+        }
+        match self.scope {
+            Scope::Env(ref env) => env
+                .borrow()
+                .get_at(Some(0), name)
+                .map(|value| value.borrow().clone()),
+            Scope::Globals(ref globals) => globals.borrow().get(name).cloned(),
+        }
+    }
+}
+
+// Module bindings often refer back to the module, so only print the name
+impl fmt::Debug for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Module({})", self.name)
+    }
+}
+
+/// Finds the file `path` refers to: relative to the directory of the
+/// importing file (or the working directory if there is none), then to each
+/// directory of the search path in turn.
+pub fn find(path: &str, importer: Option<&Path>, search_path: &[PathBuf]) -> Result<PathBuf> {
+    let base = importer.and_then(Path::parent).unwrap_or(Path::new(""));
+    std::iter::once(base)
+        .chain(search_path.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(path))
+        .find(|file| file.is_file())
+        .map_or_else(
+            || {
+                Err(ErrorKind::EvaluateError(format!(
+                    "Can't find module \"{}\".",
+                    path
+                )))
+            },
+            |file| Ok(file.canonicalize()?),
+        )
+}
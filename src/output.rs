@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Destination for everything a Lox program prints.
+pub enum Output {
+    /// Each printed line is written to the writer followed by a newline.
+    Writer(Box<dyn Write>),
+    /// The callback is invoked once per printed line, without the newline.
+    Callback(Box<dyn FnMut(&str)>),
+}
+
+impl Output {
+    pub fn stdout() -> Output {
+        Output::writer(io::stdout())
+    }
+
+    pub fn writer<W: Write + 'static>(w: W) -> Output {
+        Output::Writer(Box::new(w))
+    }
+
+    pub fn callback<F: FnMut(&str) + 'static>(f: F) -> Output {
+        Output::Callback(Box::new(f))
+    }
+
+    pub fn println(&mut self, line: &str) -> io::Result<()> {
+        match *self {
+            Output::Writer(ref mut w) => writeln!(w, "{}", line),
+            Output::Callback(ref mut f) => {
+                f(line);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Default for Output {
+    fn default() -> Output {
+        Output::stdout()
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Output::Writer(_) => write!(f, "Output::Writer"),
+            Output::Callback(_) => write!(f, "Output::Callback"),
+        }
+    }
+}
+
+/// Any writer can be used directly, e.g. `Interpreter::with_output(file)`.
+impl<W: Write + 'static> From<W> for Output {
+    fn from(w: W) -> Output {
+        Output::writer(w)
+    }
+}
+
+/// In-memory output that stays readable after being handed to an
+/// interpreter, since clones share the same underlying bytes.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> SharedBuffer {
+        SharedBuffer::default()
+    }
+
+    /// Everything written so far, decoded lossily as UTF-8.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use std::rc::Rc;
+
 use crate::ast::*;
 use crate::errors::{ErrorKind, Result};
 use crate::scanner::{Token, TokenType};
@@ -6,9 +8,9 @@ macro_rules! binary_left {
     ($self:ident, $subexpr:ident, $($op:expr),*) => {{
         let mut left = $self.$subexpr()?;
         while $self.match_any(&[$($op,)*]) {
-            let op = $self.previous().ty;
+            let tok = $self.previous().clone();
             let right = $self.$subexpr()?;
-            left = Expr::Binary(Box::new(BinaryExpr{left, op: From::from(op), right}));
+            left = Expr::Binary(Box::new(BinaryExpr{left, op: From::from(tok.ty), tok, right}));
         }
         Ok(left)
     }}
@@ -17,11 +19,17 @@ macro_rules! binary_left {
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    /// Number of loops enclosing the current statement within its function
+    loop_depth: usize,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens, current: 0 }
+        Parser {
+            tokens,
+            current: 0,
+            loop_depth: 0,
+        }
     }
 
     pub fn parse(&mut self) -> Result<Vec<Stmt>> {
@@ -35,8 +43,19 @@ impl Parser {
     fn declaration(&mut self) -> Result<Stmt> {
         let res = if self.match_any(&[TokenType::Var]) {
             self.var_declaration()
-        } else if self.match_any(&[TokenType::Fun]) {
+        } else if self.match_any(&[TokenType::Const]) {
+            self.const_declaration()
+        } else if self.check(&TokenType::Fun)
+            && (self.peek_ty(1) == TokenType::Identifier
+                || self.peek_ty(1) == TokenType::Star && self.peek_ty(2) == TokenType::Identifier)
+        {
+            // Otherwise it's an anonymous function in an expression statement
+            self.advance();
             self.fun_declaration()
+        } else if self.match_any(&[TokenType::Class]) {
+            self.class_declaration()
+        } else if self.match_any(&[TokenType::Import]) {
+            self.import_declaration()
         } else {
             self.statement()
         };
@@ -63,24 +82,218 @@ impl Parser {
                 "Expect ';' after variable declaration.",
             )?;
         }
-        Ok(Stmt::Decl(Identifier { name }, initializer))
+        Ok(Stmt::Decl(Identifier::new(name), initializer, false))
+    }
+
+    fn const_declaration(&mut self) -> Result<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect constant name.")?;
+        self.consume(TokenType::Equal, "Expect '=' after constant name.")?;
+        let initializer = self.expression()?;
+
+        if !self.is_at_end() {
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after constant declaration.",
+            )?;
+        }
+        Ok(Stmt::Decl(Identifier::new(name), initializer, true))
+    }
+
+    fn import_declaration(&mut self) -> Result<Stmt> {
+        let mut names = vec![];
+        if self.match_any(&[TokenType::LeftBrace]) {
+            loop {
+                let name = self.consume(TokenType::Identifier, "Expect name to import.")?;
+                names.push(Identifier::new(name));
+                if !self.match_any(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+            self.consume(TokenType::RightBrace, "Expect '}' after imported names.")?;
+            // `from` and `as` are only special here, so they stay usable as names
+            if !self.match_word("from") {
+                return Err(ErrorKind::ParseError {
+                    tok: self.peek().clone(),
+                    t: "Expect 'from' after imported names.".to_string(),
+                });
+            }
+        }
+        let path = self.consume(TokenType::String, "Expect module path.")?;
+        let alias = if names.is_empty() && self.match_word("as") {
+            let name = self.consume(TokenType::Identifier, "Expect module name after 'as'.")?;
+            Some(Identifier::new(name))
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after import.")?;
+        Ok(Stmt::Import(ImportStmt { path, alias, names }))
     }
 
     fn fun_declaration(&mut self) -> Result<Stmt> {
-        let name = self.consume(TokenType::Identifier, "Expect function name.")?;
-        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
-        let mut params = vec![];
+        Ok(Stmt::Func(Rc::new(self.function("function")?)))
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt> {
+        let name = self.consume(TokenType::Identifier, "Expect class name.")?;
+        let superclass = if self.match_any(&[TokenType::Less]) {
+            let superclass = self.consume(TokenType::Identifier, "Expect superclass name.")?;
+            if superclass.lexeme == name.lexeme {
+                return Err(ErrorKind::ParseError {
+                    tok: superclass,
+                    t: "A class can't inherit from itself.".to_string(),
+                });
+            }
+            Some(Identifier::new(superclass))
+        } else {
+            None
+        };
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+        let mut methods = vec![];
+        while !self.is_at_end() && !self.check(&TokenType::RightBrace) {
+            methods.push(Rc::new(self.function("method")?));
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+        Ok(Stmt::Class(ClassDecl {
+            name,
+            superclass,
+            methods,
+        }))
+    }
+
+    fn function(&mut self, kind: &str) -> Result<FunctionDecl> {
+        let is_generator = self.match_any(&[TokenType::Star]);
+        let name = self.consume(TokenType::Identifier, &format!("Expect {} name.", kind))?;
+        self.consume(
+            TokenType::LeftParen,
+            &format!("Expect '(' after {} name.", kind),
+        )?;
+        let (params, rest) = self.parameters()?;
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("Expect '{{' before {} body", kind),
+        )?;
+        let body = self.function_body()?;
+        Ok(FunctionDecl {
+            name,
+            params,
+            rest,
+            body,
+            is_generator,
+        })
+    }
+
+    /// Parses the parameter list after its opening '(' up to and including
+    /// the closing ')', returning the parameters and the rest parameter.
+    fn parameters(&mut self) -> Result<(Vec<Param>, Option<Token>)> {
+        let mut params: Vec<Param> = vec![];
+        let mut rest = None;
         if !self.check(&TokenType::RightParen) {
-            params.push(self.consume(TokenType::Identifier, "Expect identifier name.")?);
-            while self.match_any(&[TokenType::Comma]) {
-                params.push(self.consume(TokenType::Identifier, "Expect identifier name.")?);
+            loop {
+                if self.match_any(&[TokenType::DotDotDot]) {
+                    rest =
+                        Some(self.consume(TokenType::Identifier, "Expect rest parameter name.")?);
+                    break;
+                }
+                let name = self.consume(TokenType::Identifier, "Expect identifier name.")?;
+                let default = if self.match_any(&[TokenType::Equal]) {
+                    Some(self.expression()?)
+                } else {
+                    if params.last().is_some_and(|param| param.default.is_some()) {
+                        return Err(ErrorKind::ParseError {
+                            tok: name,
+                            t: "A parameter without a default can't follow one with a default."
+                                .to_string(),
+                        });
+                    }
+                    None
+                };
+                params.push(Param { name, default });
+                if !self.match_any(&[TokenType::Comma]) {
+                    break;
+                }
             }
         }
-        self.consume(TokenType::RightParen, "Expect ')' after parameters")?;
+        let message = if rest.is_some() {
+            "Expect ')' after rest parameter."
+        } else {
+            "Expect ')' after parameters"
+        };
+        self.consume(TokenType::RightParen, message)?;
+        Ok((params, rest))
+    }
 
+    /// Parses a function body after its opening '{'.
+    fn function_body(&mut self) -> Result<Vec<Stmt>> {
+        // Loops outside the function don't make `break` valid inside it
+        let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let body = self.block_stmts();
+        self.loop_depth = loop_depth;
+        body
+    }
+
+    /// Parses `fun (params) { body }` or `fun* (params) { body }` after the
+    /// `fun`.
+    fn lambda(&mut self) -> Result<Expr> {
+        let name = anonymous(self.previous());
+        let is_generator = self.match_any(&[TokenType::Star]);
+        self.consume(TokenType::LeftParen, "Expect '(' after 'fun'.")?;
+        let (params, rest) = self.parameters()?;
         self.consume(TokenType::LeftBrace, "Expect '{' before function body")?;
-        let block = self.block()?;
-        Ok(Stmt::Func(name, params, Box::new(block)))
+        let body = self.function_body()?;
+        Ok(Expr::Function(Rc::new(FunctionDecl {
+            name,
+            params,
+            rest,
+            body,
+            is_generator,
+        })))
+    }
+
+    /// Parses `(params) => body`, where the body is a block or a single
+    /// expression whose value is returned.
+    fn arrow_function(&mut self) -> Result<Expr> {
+        let name = anonymous(self.peek());
+        self.consume(TokenType::LeftParen, "Expect '(' before parameters.")?;
+        let (params, rest) = self.parameters()?;
+        let arrow = self.consume(TokenType::Arrow, "Expect '=>' after parameters.")?;
+        let body = if self.match_any(&[TokenType::LeftBrace]) {
+            self.function_body()?
+        } else {
+            let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+            let value = self.expression();
+            self.loop_depth = loop_depth;
+            vec![Stmt::Return(arrow, Some(value?))]
+        };
+        Ok(Expr::Function(Rc::new(FunctionDecl {
+            name,
+            params,
+            rest,
+            body,
+            is_generator: false,
+        })))
+    }
+
+    /// Whether the '(' at the current token starts an arrow function's
+    /// parameter list rather than a grouping, which is only clear from the
+    /// '=>' after the matching ')'.
+    fn is_arrow_function(&self) -> bool {
+        let mut depth = 0;
+        let mut distance = 0;
+        loop {
+            match self.peek_ty(distance) {
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return self.peek_ty(distance + 1) == TokenType::Arrow;
+                    }
+                }
+                TokenType::Eof => return false,
+                _ => {}
+            }
+            distance += 1;
+        }
     }
 
     fn statement(&mut self) -> Result<Stmt> {
@@ -92,10 +305,18 @@ impl Parser {
             self.while_stmt()
         } else if self.match_any(&[TokenType::Return]) {
             self.return_stmt()
+        } else if self.match_any(&[TokenType::Yield]) {
+            self.yield_stmt()
+        } else if self.match_any(&[TokenType::Break, TokenType::Continue]) {
+            self.loop_jump()
         } else if self.match_any(&[TokenType::For]) {
             self.for_stmt()
         } else if self.match_any(&[TokenType::If]) {
             self.if_statement()
+        } else if self.match_any(&[TokenType::Throw]) {
+            self.throw_stmt()
+        } else if self.match_any(&[TokenType::Try]) {
+            self.try_stmt()
         } else {
             self.expression_statement()
         }
@@ -110,35 +331,131 @@ impl Parser {
     }
 
     fn block(&mut self) -> Result<Stmt> {
+        Ok(Stmt::Block(self.block_stmts()?))
+    }
+
+    fn block_stmts(&mut self) -> Result<Vec<Stmt>> {
         let mut stmts = vec![];
         while !self.is_at_end() && !self.check(&TokenType::RightBrace) {
             stmts.push(self.declaration()?);
         }
         self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
-        Ok(Stmt::Block(stmts))
+        Ok(stmts)
     }
 
     fn while_stmt(&mut self) -> Result<Stmt> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
         let cond = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after while condition.")?;
-        let stmt = self.statement()?;
-        Ok(Stmt::While(cond, Box::new(stmt)))
+        let stmt = self.loop_body()?;
+        Ok(Stmt::While(cond, Box::new(stmt), None))
+    }
+
+    fn loop_body(&mut self) -> Result<Stmt> {
+        self.loop_depth += 1;
+        let body = self.statement();
+        self.loop_depth -= 1;
+        body
+    }
+
+    /// Parses `break;` or `continue;`, whose keyword has just been consumed.
+    fn loop_jump(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
+        if self.loop_depth == 0 {
+            return Err(ErrorKind::ParseError {
+                t: format!("Can't use '{}' outside of a loop.", keyword.lexeme),
+                tok: keyword,
+            });
+        }
+        self.consume(
+            TokenType::Semicolon,
+            &format!("Expect ';' after '{}'.", keyword.lexeme),
+        )?;
+        Ok(match keyword.ty {
+            TokenType::Break => Stmt::Break(keyword),
+            _ => Stmt::Continue(keyword),
+        })
     }
 
     fn return_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
         let expr = if self.check(&TokenType::Semicolon) {
-            Expr::Literal(Value::Nil)
+            None
         } else {
-            self.expression()?
+            Some(self.expression()?)
         };
 
         self.consume(TokenType::Semicolon, "Exprect ';' after return statement.")?;
-        Ok(Stmt::Return(expr))
+        Ok(Stmt::Return(keyword, expr))
+    }
+
+    fn yield_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
+        let value = if self.check(&TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after yielded value.")?;
+        Ok(Stmt::Yield(keyword, value))
+    }
+
+    fn throw_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
+        let value = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.")?;
+        Ok(Stmt::Throw(keyword, value))
+    }
+
+    fn try_stmt(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.")?;
+        let body = self.block_stmts()?;
+
+        let catch = if self.match_any(&[TokenType::Catch]) {
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+            let name = self.consume(TokenType::Identifier, "Expect exception variable name.")?;
+            self.consume(
+                TokenType::RightParen,
+                "Expect ')' after exception variable.",
+            )?;
+            self.consume(TokenType::LeftBrace, "Expect '{' before catch body.")?;
+            Some((Identifier::new(name), self.block_stmts()?))
+        } else {
+            None
+        };
+        let finally = if self.match_any(&[TokenType::Finally]) {
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.")?;
+            Some(self.block_stmts()?)
+        } else {
+            None
+        };
+
+        if catch.is_none() && finally.is_none() {
+            return Err(ErrorKind::ParseError {
+                tok: self.peek().clone(),
+                t: "Expect 'catch' or 'finally' after try block.".to_string(),
+            });
+        }
+        Ok(Stmt::Try(Box::new(TryStmt {
+            keyword,
+            body,
+            catch,
+            finally,
+        })))
     }
 
     fn for_stmt(&mut self) -> Result<Stmt> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        let name = usize::from(self.check(&TokenType::Var));
+        if self.peek_ty(name) == TokenType::Identifier
+            && self
+                .tokens
+                .get(self.current + name + 1)
+                .is_some_and(|tok| tok.ty == TokenType::Identifier && tok.lexeme == "in")
+        {
+            return self.for_in();
+        }
         let initializer = if self.match_any(&[TokenType::Semicolon]) {
             None
         } else if self.match_any(&[TokenType::Var]) {
@@ -153,19 +470,15 @@ impl Parser {
         };
         self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
 
-        let inc = if !self.check(&TokenType::Semicolon) {
+        let inc = if !self.check(&TokenType::RightParen) {
             Some(self.expression()?)
         } else {
             None
         };
         self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
-        let mut body = self.statement()?;
+        let body = self.loop_body()?;
 
-        if let Some(inc) = inc {
-            body = Stmt::Block(vec![body, Stmt::Expr(inc)]);
-        }
-
-        body = Stmt::While(cond, Box::new(body));
+        let mut body = Stmt::While(cond, Box::new(body), inc);
 
         if let Some(init) = initializer {
             body = Stmt::Block(vec![init, body]);
@@ -174,6 +487,23 @@ impl Parser {
         Ok(body)
     }
 
+    /// Parses the rest of `for ([var] name in iterable) body`.
+    fn for_in(&mut self) -> Result<Stmt> {
+        self.match_any(&[TokenType::Var]);
+        let name = self.consume(TokenType::Identifier, "Expect loop variable name.")?;
+        let keyword = self.peek().clone();
+        self.advance();
+        let iterable = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after iterable.")?;
+        let body = self.loop_body()?;
+        Ok(Stmt::ForIn(Box::new(ForInStmt {
+            name: Identifier::new(name),
+            keyword,
+            iterable,
+            body,
+        })))
+    }
+
     fn if_statement(&mut self) -> Result<Stmt> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
         let cond = self.expression()?;
@@ -201,22 +531,63 @@ impl Parser {
     }
 
     fn assignment(&mut self) -> Result<Expr> {
-        let expr = self.or_expr()?;
+        let expr = self.conditional()?;
         if self.match_any(&[TokenType::Equal]) {
             let equals = self.previous().clone();
             let value = self.assignment()?;
             match expr {
                 Expr::Variable(id) => Ok(Expr::Assign(id, Box::new(value))),
-                x => Err(ErrorKind::ParseError {
-                    tok: equals,
-                    t: format!("Invalid assignment target: {}", x),
-                }),
+                Expr::Get(object, name) => Ok(Expr::Set(object, name, Box::new(value))),
+                Expr::Index(target, bracket, index) => {
+                    Ok(Expr::SetIndex(target, bracket, index, Box::new(value)))
+                }
+                x => Err(invalid_target(equals, x)),
             }
+        } else if self.match_any(&[
+            TokenType::PlusEqual,
+            TokenType::MinusEqual,
+            TokenType::StarEqual,
+            TokenType::SlashEqual,
+            TokenType::PercentEqual,
+        ]) {
+            let tok = self.previous().clone();
+            let value = self.assignment()?;
+            update(expr, tok, value, false)
         } else {
             Ok(expr)
         }
     }
 
+    fn conditional(&mut self) -> Result<Expr> {
+        let cond = self.coalesce()?;
+        if self.match_any(&[TokenType::Question]) {
+            let then = self.expression()?;
+            self.consume(TokenType::Colon, "Expect ':' after then branch of '?'.")?;
+            let otherwise = self.conditional()?;
+            return Ok(Expr::Conditional(
+                Box::new(cond),
+                Box::new(then),
+                Box::new(otherwise),
+            ));
+        }
+        Ok(cond)
+    }
+
+    fn coalesce(&mut self) -> Result<Expr> {
+        let mut left = self.or_expr()?;
+
+        while self.match_any(&[TokenType::QuestionQuestion]) {
+            let op = self.previous().ty;
+            let right = self.or_expr()?;
+            left = Expr::Logical(Box::new(LogicalExpr {
+                left,
+                op: op.into(),
+                right,
+            }));
+        }
+        Ok(left)
+    }
+
     fn or_expr(&mut self) -> Result<Expr> {
         let mut left = self.and_expr()?;
 
@@ -259,7 +630,7 @@ impl Parser {
     fn comparison(&mut self) -> Result<Expr> {
         binary_left!(
             self,
-            addition,
+            bitwise_or,
             TokenType::Greater,
             TokenType::GreaterEqual,
             TokenType::Less,
@@ -267,52 +638,178 @@ impl Parser {
         )
     }
 
+    fn bitwise_or(&mut self) -> Result<Expr> {
+        binary_left!(self, bitwise_xor, TokenType::Pipe)
+    }
+
+    fn bitwise_xor(&mut self) -> Result<Expr> {
+        binary_left!(self, bitwise_and, TokenType::Caret)
+    }
+
+    fn bitwise_and(&mut self) -> Result<Expr> {
+        binary_left!(self, shift, TokenType::Ampersand)
+    }
+
+    fn shift(&mut self) -> Result<Expr> {
+        binary_left!(
+            self,
+            addition,
+            TokenType::LessLess,
+            TokenType::GreaterGreater
+        )
+    }
+
     fn addition(&mut self) -> Result<Expr> {
         binary_left!(self, multiplication, TokenType::Minus, TokenType::Plus)
     }
 
     fn multiplication(&mut self) -> Result<Expr> {
-        binary_left!(self, unary, TokenType::Slash, TokenType::Star)
+        binary_left!(
+            self,
+            unary,
+            TokenType::Slash,
+            TokenType::Star,
+            TokenType::Percent,
+            TokenType::TildeSlash
+        )
     }
 
     fn unary(&mut self) -> Result<Expr> {
-        if self.match_any(&[TokenType::Minus, TokenType::Bang]) {
-            let op = self.previous().ty;
+        if self.match_any(&[TokenType::PlusPlus, TokenType::MinusMinus]) {
+            let tok = self.previous().clone();
+            let target = self.unary()?;
+            // Lox reads `--(3)` as two negations, so only an assignable
+            // target makes `--` a decrement
+            if tok.ty == TokenType::MinusMinus && !is_assignable(&target) {
+                return Ok(double_negation(tok, target));
+            }
+            return update(target, tok, Expr::Literal(Value::Number(1.0)), false);
+        }
+        if self.match_any(&[TokenType::Minus, TokenType::Bang, TokenType::Tilde]) {
+            let tok = self.previous().clone();
             let expr = self.unary()?;
             return Ok(Expr::Unary(Box::new(UnaryExpr {
-                op: From::from(op),
+                op: From::from(tok.ty),
+                tok,
                 expr,
             })));
         }
 
-        self.call()
+        self.power()
+    }
+
+    /// `**` binds tighter than unary operators on its left, so `-2 ** 2` is
+    /// -4, and is right-associative.
+    fn power(&mut self) -> Result<Expr> {
+        let left = self.postfix()?;
+        if self.match_any(&[TokenType::StarStar]) {
+            let tok = self.previous().clone();
+            let right = self.unary()?;
+            return Ok(Expr::Binary(Box::new(BinaryExpr {
+                left,
+                op: BinaryOperator::StarStar,
+                tok,
+                right,
+            })));
+        }
+        Ok(left)
+    }
+
+    fn postfix(&mut self) -> Result<Expr> {
+        let expr = self.call()?;
+        if self.match_any(&[TokenType::PlusPlus, TokenType::MinusMinus]) {
+            let tok = self.previous().clone();
+            return update(expr, tok, Expr::Literal(Value::Number(1.0)), true);
+        }
+        Ok(expr)
     }
 
     fn call(&mut self) -> Result<Expr> {
         let mut expr = self.primary()?;
+        // The object of the optional chain being parsed, if any
+        let mut chain: Option<Expr> = None;
 
         loop {
-            if self.match_any(&[TokenType::LeftParen]) {
+            if self.match_any(&[TokenType::QuestionDot]) {
+                let name =
+                    self.consume(TokenType::Identifier, "Expect property name after '?.'.")?;
+                // A further `?.` nests the chain so far inside a new one
+                let object = match chain.take() {
+                    Some(object) => Expr::Chain(Box::new(object), Box::new(expr)),
+                    None => expr,
+                };
+                chain = Some(object);
+                expr = Expr::Get(Box::new(Expr::Chained), name);
+            } else if self.match_any(&[TokenType::LeftParen]) {
                 expr = self.finish_call(expr)?;
+            } else if self.match_any(&[TokenType::Dot]) {
+                let name =
+                    self.consume(TokenType::Identifier, "Expect property name after '.'.")?;
+                expr = Expr::Get(Box::new(expr), name);
+            } else if self.match_any(&[TokenType::LeftBracket]) {
+                let bracket = self.previous().clone();
+                let index = self.expression()?;
+                self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+                expr = Expr::Index(Box::new(expr), bracket, Box::new(index));
             } else {
                 break;
             }
         }
 
-        Ok(expr)
+        Ok(match chain {
+            Some(object) => Expr::Chain(Box::new(object), Box::new(expr)),
+            None => expr,
+        })
     }
 
     fn finish_call(&mut self, expr: Expr) -> Result<Expr> {
-        let mut args = vec![];
+        let mut args: Vec<Arg> = vec![];
         if !self.check(&TokenType::RightParen) {
-            args.push(self.expression()?);
-            while self.match_any(&[TokenType::Comma]) {
-                args.push(self.expression()?);
+            loop {
+                let start = self.peek().clone();
+                let arg = self.argument()?;
+                match arg {
+                    Arg::Named(ref name, _)
+                        if args.iter().any(|other| other.name() == Some(&name.lexeme)) =>
+                    {
+                        return Err(ErrorKind::ParseError {
+                            tok: name.clone(),
+                            t: format!("Can't pass argument '{}' twice.", name.lexeme),
+                        });
+                    }
+                    Arg::Positional(_) | Arg::Spread(..)
+                        if matches!(args.last(), Some(Arg::Named(..))) =>
+                    {
+                        return Err(ErrorKind::ParseError {
+                            tok: start,
+                            t: "Can't pass a positional argument after a named one.".to_string(),
+                        });
+                    }
+                    _ => {}
+                }
+                args.push(arg);
+                if !self.match_any(&[TokenType::Comma]) {
+                    break;
+                }
             }
         }
-        self.consume(TokenType::RightParen, "Expect ')' after arguments")?;
+        let paren = self.consume(TokenType::RightParen, "Expect ')' after arguments")?;
 
-        Ok(Expr::Call(Box::new(expr), args))
+        Ok(Expr::Call(Box::new(expr), paren, args))
+    }
+
+    fn argument(&mut self) -> Result<Arg> {
+        if self.check(&TokenType::Identifier) && self.peek_ty(1) == TokenType::Colon {
+            self.advance();
+            let name = self.previous().clone();
+            self.advance();
+            return Ok(Arg::Named(name, self.expression()?));
+        }
+        if self.match_any(&[TokenType::DotDotDot]) {
+            let tok = self.previous().clone();
+            return Ok(Arg::Spread(tok, self.expression()?));
+        }
+        Ok(Arg::Positional(self.expression()?))
     }
 
     fn primary(&mut self) -> Result<Expr> {
@@ -326,9 +823,22 @@ impl Parser {
             return Ok(Expr::Literal(Value::Bool(true)));
         }
         if self.match_any(&[TokenType::Identifier]) {
-            return Ok(Expr::Variable(Identifier {
-                name: self.previous().clone(),
-            }));
+            return Ok(Expr::Variable(Identifier::new(self.previous().clone())));
+        }
+        if self.match_any(&[TokenType::This]) {
+            return Ok(Expr::This(Identifier::new(self.previous().clone())));
+        }
+        if self.match_any(&[TokenType::Fun]) {
+            return self.lambda();
+        }
+        if self.check(&TokenType::LeftParen) && self.is_arrow_function() {
+            return self.arrow_function();
+        }
+        if self.match_any(&[TokenType::Super]) {
+            let keyword = Identifier::new(self.previous().clone());
+            self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+            let method = self.consume(TokenType::Identifier, "Expect superclass method name.")?;
+            return Ok(Expr::Super(keyword, method));
         }
         let token = self.peek();
         match token.ty {
@@ -337,11 +847,61 @@ impl Parser {
                 Ok(Expr::Literal(Value::Number(n)))
             }
             TokenType::String => {
-                // Ignore double quotes at start and end
-                let s = token.lexeme[1..token.lexeme.len() - 1].to_string();
+                let s = token.literal.as_deref().unwrap_or_default().to_string();
                 self.advance();
                 Ok(Expr::Literal(Value::String(s)))
             }
+            TokenType::Interpolation => {
+                let mut parts = vec![];
+                while self.match_any(&[TokenType::Interpolation]) {
+                    let start = self.previous().clone();
+                    let literal = start.literal.as_deref().unwrap_or_default();
+                    parts.push(Expr::Literal(Value::String(literal.to_string())));
+                    // In `${}` the `}` is scanned as the start of the rest of
+                    // the string
+                    if self.check(&TokenType::String) && self.peek().lexeme.starts_with('}') {
+                        return Err(ErrorKind::ParseError {
+                            tok: start,
+                            t: "Expect expression in interpolation.".to_string(),
+                        });
+                    }
+                    parts.push(self.expression()?);
+                }
+                // The scanner always ends an interpolated string with a plain
+                // string token holding the text after the last `}`
+                let end = self.consume(TokenType::String, "Expect '}' after interpolation.")?;
+                let literal = end.literal.as_deref().unwrap_or_default();
+                parts.push(Expr::Literal(Value::String(literal.to_string())));
+                Ok(Expr::Interpolate(parts))
+            }
+            TokenType::LeftBracket => {
+                let bracket = token.clone();
+                self.advance();
+                let mut items = vec![];
+                while !self.check(&TokenType::RightBracket) {
+                    items.push(self.expression()?);
+                    if !self.match_any(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+                self.consume(TokenType::RightBracket, "Expect ']' after list items.")?;
+                Ok(Expr::List(bracket, items))
+            }
+            TokenType::LeftBrace => {
+                let brace = token.clone();
+                self.advance();
+                let mut entries = vec![];
+                while !self.check(&TokenType::RightBrace) {
+                    let key = self.expression()?;
+                    self.consume(TokenType::Colon, "Expect ':' after map key.")?;
+                    entries.push((key, self.expression()?));
+                    if !self.match_any(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+                self.consume(TokenType::RightBrace, "Expect '}' after map entries.")?;
+                Ok(Expr::Map(brace, entries))
+            }
             TokenType::LeftParen => {
                 self.advance();
                 let expr = self.expression()?;
@@ -365,11 +925,18 @@ impl Parser {
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
+                | TokenType::Import
                 | TokenType::Print
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
-                | TokenType::Return => {
+                | TokenType::Return
+                | TokenType::Yield
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Try
+                | TokenType::Throw => {
                     return;
                 }
                 _ => {}
@@ -400,6 +967,17 @@ impl Parser {
         false
     }
 
+    /// Consumes an identifier spelled `word`, for words that are only
+    /// keywords in some places.
+    fn match_word(&mut self, word: &str) -> bool {
+        if self.check(&TokenType::Identifier) && self.peek().lexeme == word {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
     fn check(&mut self, token_type: &TokenType) -> bool {
         &self.peek().ty == token_type
     }
@@ -419,4 +997,55 @@ impl Parser {
     fn previous(&self) -> &Token {
         &self.tokens[self.current - 1]
     }
+
+    /// The type of the token `distance` ahead of the current one.
+    fn peek_ty(&self, distance: usize) -> TokenType {
+        self.tokens
+            .get(self.current + distance)
+            .map_or(TokenType::Eof, |tok| tok.ty)
+    }
+}
+
+/// The name token given to a function expression starting at `tok`.
+fn anonymous(tok: &Token) -> Token {
+    Token::new(TokenType::Identifier, "anonymous", tok.line, tok.col)
+}
+
+fn invalid_target(tok: Token, target: Expr) -> ErrorKind {
+    ErrorKind::ParseError {
+        tok,
+        t: format!("Invalid assignment target: {}", target),
+    }
+}
+
+/// `-(-expr)`, for a `--` that isn't a decrement.
+fn double_negation(tok: Token, expr: Expr) -> Expr {
+    let minus = |col| Token::new(TokenType::Minus, "-", tok.line, col);
+    let negate = |tok: Token, expr| {
+        Expr::Unary(Box::new(UnaryExpr {
+            op: UnaryOperator::Minus,
+            tok,
+            expr,
+        }))
+    };
+    negate(minus(tok.col), negate(minus(tok.col + 1), expr))
+}
+
+fn is_assignable(target: &Expr) -> bool {
+    matches!(*target, Expr::Variable(_) | Expr::Get(..) | Expr::Index(..))
+}
+
+/// Builds a compound assignment or increment, checking that `target` is
+/// something that can be assigned to.
+fn update(target: Expr, tok: Token, value: Expr, postfix: bool) -> Result<Expr> {
+    if !is_assignable(&target) {
+        return Err(invalid_target(tok, target));
+    }
+    Ok(Expr::Update(Box::new(UpdateExpr {
+        target,
+        op: From::from(tok.ty),
+        tok,
+        value,
+        postfix,
+    })))
 }
@@ -0,0 +1,452 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::errors::{ErrorKind, Result};
+use crate::scanner::Token;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Method,
+    Initializer,
+    Generator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+/// Static pass run between parsing and interpretation. Records how many
+/// scopes separate each variable use from its declaration and rejects
+/// programs that misuse scopes, `return`, `this` or `super`.
+pub struct Resolver {
+    /// One map per enclosing block; the flag is set once the variable's
+    /// initializer has been resolved.
+    scopes: Vec<HashMap<String, bool>>,
+    /// The names declared with `const` in each of `scopes`
+    constants: Vec<HashSet<String>>,
+    /// Top-level names declared with `const` so far
+    global_constants: HashSet<String>,
+    function: FunctionType,
+    class: ClassType,
+}
+
+pub trait Resolvable {
+    fn resolve(&self, resolver: &mut Resolver) -> Result<()>;
+}
+
+impl Default for Resolver {
+    fn default() -> Resolver {
+        Resolver::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver {
+            scopes: vec![],
+            constants: vec![],
+            global_constants: HashSet::new(),
+            function: FunctionType::None,
+            class: ClassType::None,
+        }
+    }
+
+    pub fn resolve(&mut self, stmts: &[Stmt]) -> Result<()> {
+        for stmt in stmts {
+            stmt.resolve(self)?;
+        }
+        Ok(())
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+        self.constants.push(HashSet::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+        self.constants.pop();
+    }
+
+    fn declare(&mut self, name: &Token) -> Result<()> {
+        if self.scopes.is_empty() && self.global_constants.contains(&name.lexeme) {
+            return Err(error(
+                name,
+                &format!("Can't redeclare constant '{}'.", name.lexeme),
+            ));
+        }
+        if let Some(scope) = self.scopes.last_mut() {
+            if scope.contains_key(&name.lexeme) {
+                return Err(error(
+                    name,
+                    "Already a variable with this name in this scope.",
+                ));
+            }
+            scope.insert(name.lexeme.clone(), false);
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.lexeme.clone(), true);
+        }
+    }
+
+    /// Marks the just declared `name` as a constant.
+    fn make_constant(&mut self, name: &Token) {
+        match self.constants.last_mut() {
+            Some(constants) => constants.insert(name.lexeme.clone()),
+            None => self.global_constants.insert(name.lexeme.clone()),
+        };
+    }
+
+    /// Rejects assignments to a constant declared earlier in the script.
+    fn check_assignable(&self, name: &Token) -> Result<()> {
+        let constant = match self
+            .scopes
+            .iter()
+            .rposition(|scope| scope.contains_key(&name.lexeme))
+        {
+            Some(i) => self.constants[i].contains(&name.lexeme),
+            None => self.global_constants.contains(&name.lexeme),
+        };
+        if constant {
+            return Err(error(
+                name,
+                &format!("Can't assign to constant '{}'.", name.lexeme),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Defines a name the interpreter binds implicitly, like `this` and `super`.
+    fn define_implicit(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), true);
+        }
+    }
+
+    fn resolve_local(&mut self, id: &Identifier) {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if scope.contains_key(&id.name.lexeme) {
+                id.depth.set(Some(depth));
+                return;
+            }
+        }
+        // Not found in any enclosing block, so it must be a global
+        id.depth.set(None);
+    }
+
+    fn resolve_block(&mut self, stmts: &[Stmt]) -> Result<()> {
+        self.begin_scope();
+        let res = self.resolve(stmts);
+        self.end_scope();
+        res
+    }
+
+    fn resolve_function(&mut self, decl: &FunctionDecl, ty: FunctionType) -> Result<()> {
+        let enclosing = self.function;
+        self.function = if decl.is_generator {
+            FunctionType::Generator
+        } else {
+            ty
+        };
+        self.begin_scope();
+        let res = self.resolve_params_and_body(decl);
+        self.end_scope();
+        self.function = enclosing;
+        res
+    }
+
+    fn resolve_params_and_body(&mut self, decl: &FunctionDecl) -> Result<()> {
+        // Defaults can refer to the parameters before them
+        for param in &decl.params {
+            if let Some(ref default) = param.default {
+                default.resolve(self)?;
+            }
+            self.declare(&param.name)?;
+            self.define(&param.name);
+        }
+        if let Some(ref rest) = decl.rest {
+            self.declare(rest)?;
+            self.define(rest);
+        }
+        self.resolve(&decl.body)
+    }
+
+    fn resolve_class(&mut self, decl: &ClassDecl) -> Result<()> {
+        if let Some(ref superclass) = decl.superclass {
+            self.resolve_local(superclass);
+            self.begin_scope();
+            self.define_implicit("super");
+        }
+
+        self.begin_scope();
+        self.define_implicit("this");
+        let mut res = Ok(());
+        for method in &decl.methods {
+            let ty = if method.name.lexeme == "init" {
+                if method.is_generator {
+                    res = Err(error(
+                        &method.name,
+                        "Can't make an initializer a generator.",
+                    ));
+                    break;
+                }
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            res = self.resolve_function(method, ty);
+            if res.is_err() {
+                break;
+            }
+        }
+        self.end_scope();
+
+        if decl.superclass.is_some() {
+            self.end_scope();
+        }
+        res
+    }
+}
+
+fn error(tok: &Token, t: &str) -> ErrorKind {
+    ErrorKind::ResolveError {
+        tok: tok.clone(),
+        t: t.to_string(),
+    }
+}
+
+impl Resolvable for Stmt {
+    fn resolve(&self, resolver: &mut Resolver) -> Result<()> {
+        match *self {
+            Stmt::Expr(ref expr) | Stmt::Print(ref expr) => expr.resolve(resolver),
+            Stmt::Decl(ref id, ref expr, constant) => {
+                resolver.declare(&id.name)?;
+                expr.resolve(resolver)?;
+                resolver.define(&id.name);
+                if constant {
+                    resolver.make_constant(&id.name);
+                }
+                Ok(())
+            }
+            Stmt::Block(ref stmts) => resolver.resolve_block(stmts),
+            Stmt::Return(ref keyword, ref expr) => {
+                if resolver.function == FunctionType::None {
+                    return Err(error(keyword, "Can't return from top-level code."));
+                }
+                if let Some(ref expr) = *expr {
+                    if resolver.function == FunctionType::Initializer {
+                        return Err(error(keyword, "Can't return a value from an initializer."));
+                    }
+                    if resolver.function == FunctionType::Generator {
+                        return Err(error(keyword, "Can't return a value from a generator."));
+                    }
+                    expr.resolve(resolver)?;
+                }
+                Ok(())
+            }
+            Stmt::Yield(ref keyword, ref value) => {
+                if resolver.function != FunctionType::Generator {
+                    return Err(error(keyword, "Can't yield outside of a generator."));
+                }
+                match *value {
+                    Some(ref value) => value.resolve(resolver),
+                    None => Ok(()),
+                }
+            }
+            Stmt::If(ref cond, ref if_stmt, ref else_stmt) => {
+                cond.resolve(resolver)?;
+                if_stmt.resolve(resolver)?;
+                if let Some(ref else_stmt) = *else_stmt {
+                    else_stmt.resolve(resolver)?;
+                }
+                Ok(())
+            }
+            Stmt::While(ref cond, ref stmt, ref increment) => {
+                cond.resolve(resolver)?;
+                stmt.resolve(resolver)?;
+                match *increment {
+                    Some(ref increment) => increment.resolve(resolver),
+                    None => Ok(()),
+                }
+            }
+            Stmt::ForIn(ref f) => {
+                f.iterable.resolve(resolver)?;
+                resolver.begin_scope();
+                let res = resolver.declare(&f.name.name).and_then(|_| {
+                    resolver.define(&f.name.name);
+                    f.body.resolve(resolver)
+                });
+                resolver.end_scope();
+                res
+            }
+            Stmt::Break(_) | Stmt::Continue(_) => Ok(()),
+            Stmt::Import(ref import) => {
+                for name in import.alias.iter().chain(&import.names) {
+                    resolver.declare(&name.name)?;
+                    resolver.define(&name.name);
+                }
+                Ok(())
+            }
+            Stmt::Throw(_, ref value) => value.resolve(resolver),
+            Stmt::Try(ref t) => {
+                resolver.resolve_block(&t.body)?;
+                if let Some((ref name, ref handler)) = t.catch {
+                    // The caught value lives in the same scope as the handler
+                    resolver.begin_scope();
+                    let res = resolver.declare(&name.name).and_then(|_| {
+                        resolver.define(&name.name);
+                        resolver.resolve(handler)
+                    });
+                    resolver.end_scope();
+                    res?;
+                }
+                match t.finally {
+                    Some(ref finally) => resolver.resolve_block(finally),
+                    None => Ok(()),
+                }
+            }
+            Stmt::Func(ref decl) => {
+                // Defined before the body so the function can recurse
+                resolver.declare(&decl.name)?;
+                resolver.define(&decl.name);
+                resolver.resolve_function(decl, FunctionType::Function)
+            }
+            Stmt::Class(ref decl) => {
+                resolver.declare(&decl.name)?;
+                resolver.define(&decl.name);
+
+                let enclosing = resolver.class;
+                resolver.class = if decl.superclass.is_some() {
+                    ClassType::Subclass
+                } else {
+                    ClassType::Class
+                };
+                let res = resolver.resolve_class(decl);
+                resolver.class = enclosing;
+                res
+            }
+        }
+    }
+}
+
+impl Resolvable for Expr {
+    fn resolve(&self, resolver: &mut Resolver) -> Result<()> {
+        match *self {
+            Expr::Literal(_) => Ok(()),
+            Expr::Unary(ref u) => u.expr.resolve(resolver),
+            Expr::Binary(ref b) => {
+                b.left.resolve(resolver)?;
+                b.right.resolve(resolver)
+            }
+            Expr::Logical(ref l) => {
+                l.left.resolve(resolver)?;
+                l.right.resolve(resolver)
+            }
+            Expr::Grouping(ref g) => g.expr.resolve(resolver),
+            Expr::Call(ref callee, _, ref args) => {
+                callee.resolve(resolver)?;
+                for arg in args {
+                    match *arg {
+                        Arg::Positional(ref value)
+                        | Arg::Spread(_, ref value)
+                        | Arg::Named(_, ref value) => value.resolve(resolver)?,
+                    }
+                }
+                Ok(())
+            }
+            Expr::List(_, ref items) | Expr::Interpolate(ref items) => {
+                for item in items {
+                    item.resolve(resolver)?;
+                }
+                Ok(())
+            }
+            Expr::Map(_, ref entries) => {
+                for (key, value) in entries {
+                    key.resolve(resolver)?;
+                    value.resolve(resolver)?;
+                }
+                Ok(())
+            }
+            Expr::Index(ref target, _, ref index) => {
+                target.resolve(resolver)?;
+                index.resolve(resolver)
+            }
+            Expr::SetIndex(ref target, _, ref index, ref value) => {
+                target.resolve(resolver)?;
+                index.resolve(resolver)?;
+                value.resolve(resolver)
+            }
+            Expr::Get(ref object, _) => object.resolve(resolver),
+            Expr::Chain(ref object, ref rest) => {
+                object.resolve(resolver)?;
+                rest.resolve(resolver)
+            }
+            Expr::Chained => Ok(()),
+            Expr::Conditional(ref cond, ref then, ref otherwise) => {
+                cond.resolve(resolver)?;
+                then.resolve(resolver)?;
+                otherwise.resolve(resolver)
+            }
+            Expr::Set(ref object, _, ref value) => {
+                object.resolve(resolver)?;
+                value.resolve(resolver)
+            }
+            Expr::Variable(ref id) => {
+                if let Some(scope) = resolver.scopes.last() {
+                    if scope.get(&id.name.lexeme) == Some(&false) {
+                        return Err(error(
+                            &id.name,
+                            "Can't read local variable in its own initializer.",
+                        ));
+                    }
+                }
+                resolver.resolve_local(id);
+                Ok(())
+            }
+            Expr::Assign(ref id, ref value) => {
+                value.resolve(resolver)?;
+                resolver.check_assignable(&id.name)?;
+                resolver.resolve_local(id);
+                Ok(())
+            }
+            Expr::Function(ref decl) => resolver.resolve_function(decl, FunctionType::Function),
+            Expr::Update(ref u) => {
+                if let Expr::Variable(ref id) = u.target {
+                    resolver.check_assignable(&id.name)?;
+                }
+                u.target.resolve(resolver)?;
+                u.value.resolve(resolver)
+            }
+            Expr::This(ref keyword) => {
+                if resolver.class == ClassType::None {
+                    return Err(error(&keyword.name, "Can't use 'this' outside of a class."));
+                }
+                resolver.resolve_local(keyword);
+                Ok(())
+            }
+            Expr::Super(ref keyword, _) => match resolver.class {
+                ClassType::None => Err(error(
+                    &keyword.name,
+                    "Can't use 'super' outside of a class.",
+                )),
+                ClassType::Class => Err(error(
+                    &keyword.name,
+                    "Can't use 'super' in a class with no superclass.",
+                )),
+                ClassType::Subclass => {
+                    resolver.resolve_local(keyword);
+                    Ok(())
+                }
+            },
+        }
+    }
+}
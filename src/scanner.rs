@@ -6,21 +6,30 @@ lazy_static! {
     static ref KEYWORDS: HashMap<String, TokenType> = {
         let mut k = HashMap::new();
         k.insert("and".to_string(), TokenType::And);
+        k.insert("break".to_string(), TokenType::Break);
+        k.insert("catch".to_string(), TokenType::Catch);
         k.insert("class".to_string(), TokenType::Class);
+        k.insert("const".to_string(), TokenType::Const);
+        k.insert("continue".to_string(), TokenType::Continue);
         k.insert("else".to_string(), TokenType::Else);
         k.insert("false".to_string(), TokenType::False);
+        k.insert("finally".to_string(), TokenType::Finally);
         k.insert("for".to_string(), TokenType::For);
         k.insert("fun".to_string(), TokenType::Fun);
         k.insert("if".to_string(), TokenType::If);
+        k.insert("import".to_string(), TokenType::Import);
         k.insert("nil".to_string(), TokenType::Nil);
         k.insert("or".to_string(), TokenType::Or);
         k.insert("print".to_string(), TokenType::Print);
         k.insert("return".to_string(), TokenType::Return);
         k.insert("super".to_string(), TokenType::Super);
         k.insert("this".to_string(), TokenType::This);
+        k.insert("throw".to_string(), TokenType::Throw);
         k.insert("true".to_string(), TokenType::True);
+        k.insert("try".to_string(), TokenType::Try);
         k.insert("var".to_string(), TokenType::Var);
         k.insert("while".to_string(), TokenType::While);
+        k.insert("yield".to_string(), TokenType::Yield);
         k
     };
 }
@@ -32,13 +41,21 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
     Semicolon,
     Slash,
     Star,
+    Percent,
+    Tilde,
+    Ampersand,
+    Pipe,
+    Caret,
 
     // One or two character tokens
     Bang,
@@ -49,29 +66,56 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    LessLess,
+    GreaterGreater,
+    StarStar,
+    TildeSlash,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PercentEqual,
+    PlusPlus,
+    MinusMinus,
+    Arrow,
+    Question,
+    QuestionQuestion,
+    QuestionDot,
+    DotDotDot,
 
     // Literals
     Identifier,
     String,
+    /// The part of an interpolated string up to a `${`
+    Interpolation,
     Number(f64),
 
     // Keywords
     And,
+    Break,
+    Catch,
     Class,
+    Const,
+    Continue,
     Else,
     False,
+    Finally,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
+    Yield,
 
     Eof,
 }
@@ -81,44 +125,66 @@ pub struct Token {
     pub ty: TokenType,
     pub lexeme: String,
     pub line: usize,
+    /// 1-based column of the token's first character
+    pub col: usize,
+    /// The decoded contents of a string literal
+    pub literal: Option<Box<str>>,
 }
 
 impl Token {
-    pub fn new<S: AsRef<str>>(ty: TokenType, lexeme: S, line: usize) -> Token {
+    pub fn new<S: AsRef<str>>(ty: TokenType, lexeme: S, line: usize, col: usize) -> Token {
         Token {
             ty,
             lexeme: lexeme.as_ref().to_string(),
             line,
+            col,
+            literal: None,
         }
     }
 }
 
 pub struct Scanner {
-    src: String,
+    src: Vec<char>,
     tokens: Vec<Token>,
     line: usize,
+    line_start: usize,
     start: usize,
+    /// Where the current token starts, since strings can span lines
+    start_line: usize,
+    start_col: usize,
     current: usize,
+    /// Brace depth inside each `${` that is still open, innermost last
+    interpolations: Vec<usize>,
 }
 
 impl Scanner {
     pub fn new<S: AsRef<str>>(s: S) -> Scanner {
         Scanner {
-            src: s.as_ref().to_owned(),
+            src: s.as_ref().chars().collect(),
             tokens: vec![],
             line: 1,
+            line_start: 0,
             start: 0,
+            start_line: 1,
+            start_col: 1,
             current: 0,
+            interpolations: vec![],
         }
     }
 
     pub fn scan_tokens(mut self) -> Result<Vec<Token>> {
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_col = self.current - self.line_start + 1;
             self.scan_token()?;
         }
+        if !self.interpolations.is_empty() {
+            self.error("Unterminated string interpolation.".to_string())?;
+        }
+        self.start_col = self.current - self.line_start + 1;
         self.tokens
-            .push(Token::new(TokenType::Eof, "Eof", self.line));
+            .push(Token::new(TokenType::Eof, "Eof", self.line, self.start_col));
         Ok(self.tokens)
     }
 
@@ -127,14 +193,96 @@ impl Scanner {
         match c {
             '(' => self.add_token(TokenType::LeftParen),
             ')' => self.add_token(TokenType::RightParen),
-            '{' => self.add_token(TokenType::LeftBrace),
-            '}' => self.add_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.add_token(TokenType::LeftBrace);
+            }
+            '}' => match self.interpolations.last_mut() {
+                // Closes a `${`, so the string continues
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()?;
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.add_token(TokenType::RightBrace);
+                }
+                None => self.add_token(TokenType::RightBrace),
+            },
+            '[' => self.add_token(TokenType::LeftBracket),
+            ']' => self.add_token(TokenType::RightBracket),
             ',' => self.add_token(TokenType::Comma),
-            '.' => self.add_token(TokenType::Dot),
-            '-' => self.add_token(TokenType::Minus),
-            '+' => self.add_token(TokenType::Plus),
+            ':' => self.add_token(TokenType::Colon),
+            '.' => {
+                if self.peek() == Some('.') && self.peek_next() == Some('.') {
+                    self.current += 2;
+                    self.add_token(TokenType::DotDotDot);
+                } else {
+                    self.add_token(TokenType::Dot);
+                }
+            }
+            '-' => {
+                let token = if self.match_next('-') {
+                    TokenType::MinusMinus
+                } else if self.match_next('=') {
+                    TokenType::MinusEqual
+                } else {
+                    TokenType::Minus
+                };
+                self.add_token(token);
+            }
+            '+' => {
+                let token = if self.match_next('+') {
+                    TokenType::PlusPlus
+                } else if self.match_next('=') {
+                    TokenType::PlusEqual
+                } else {
+                    TokenType::Plus
+                };
+                self.add_token(token);
+            }
             ';' => self.add_token(TokenType::Semicolon),
-            '*' => self.add_token(TokenType::Star),
+            '*' => {
+                let token = if self.match_next('*') {
+                    TokenType::StarStar
+                } else if self.match_next('=') {
+                    TokenType::StarEqual
+                } else {
+                    TokenType::Star
+                };
+                self.add_token(token);
+            }
+            '%' => {
+                let token = if self.match_next('=') {
+                    TokenType::PercentEqual
+                } else {
+                    TokenType::Percent
+                };
+                self.add_token(token);
+            }
+            '~' => {
+                let token = if self.match_next('/') {
+                    TokenType::TildeSlash
+                } else {
+                    TokenType::Tilde
+                };
+                self.add_token(token);
+            }
+            '?' => {
+                let token = if self.match_next('?') {
+                    TokenType::QuestionQuestion
+                } else if self.match_next('.') {
+                    TokenType::QuestionDot
+                } else {
+                    TokenType::Question
+                };
+                self.add_token(token);
+            }
+            '&' => self.add_token(TokenType::Ampersand),
+            '|' => self.add_token(TokenType::Pipe),
+            '^' => self.add_token(TokenType::Caret),
             '!' => {
                 let token = if self.match_next('=') {
                     TokenType::BangEqual
@@ -146,6 +294,8 @@ impl Scanner {
             '=' => {
                 let token = if self.match_next('=') {
                     TokenType::EqualEqual
+                } else if self.match_next('>') {
+                    TokenType::Arrow
                 } else {
                     TokenType::Equal
                 };
@@ -154,6 +304,8 @@ impl Scanner {
             '<' => {
                 let token = if self.match_next('=') {
                     TokenType::LessEqual
+                } else if self.match_next('<') {
+                    TokenType::LessLess
                 } else {
                     TokenType::Less
                 };
@@ -162,6 +314,8 @@ impl Scanner {
             '>' => {
                 let token = if self.match_next('=') {
                     TokenType::GreaterEqual
+                } else if self.match_next('>') {
+                    TokenType::GreaterGreater
                 } else {
                     TokenType::Greater
                 };
@@ -172,6 +326,8 @@ impl Scanner {
                     while self.peek() != Some('\n') && !self.is_at_end() {
                         self.advance();
                     }
+                } else if self.match_next('=') {
+                    self.add_token(TokenType::SlashEqual);
                 } else {
                     self.add_token(TokenType::Slash);
                 }
@@ -180,7 +336,7 @@ impl Scanner {
             c if is_digit(c) => self.number()?,
             c if is_alpha(c) => self.identifier(),
             ' ' | '\t' | '\r' => {}
-            '\n' => self.line += 1,
+            '\n' => self.newline(),
             _ => {
                 return Err(ErrorKind::ScanError(
                     self.line,
@@ -191,12 +347,27 @@ impl Scanner {
         Ok(())
     }
 
+    /// Scans the rest of a string literal, or of one of its parts if it
+    /// contains `${expr}` interpolations.
     fn string(&mut self) -> Result<()> {
+        let mut value = String::new();
         while self.peek() != Some('"') && !self.is_at_end() {
-            if self.peek() == Some('\n') {
-                self.line += 1;
+            if self.peek() == Some('$') && self.peek_next() == Some('{') {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                self.add_token(TokenType::Interpolation);
+                self.tokens.last_mut().unwrap().literal = Some(value.into());
+                return Ok(());
+            }
+            match self.advance() {
+                '\n' => {
+                    self.newline();
+                    value.push('\n');
+                }
+                '\\' => value.push(self.escape()?),
+                c => value.push(c),
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -206,9 +377,66 @@ impl Scanner {
         self.advance(); // Closing "
 
         self.add_token(TokenType::String);
+        self.tokens.last_mut().unwrap().literal = Some(value.into());
         Ok(())
     }
 
+    /// Decodes the escape sequence after a backslash in a string.
+    fn escape(&mut self) -> Result<char> {
+        let col = self.current - self.line_start;
+        let c = match self.peek() {
+            Some(c) => c,
+            None => {
+                return Err(ErrorKind::ScanError(
+                    self.line,
+                    "Unterminated string.".to_string(),
+                ))
+            }
+        };
+        self.advance();
+        match c {
+            '"' => Ok('"'),
+            '\\' => Ok('\\'),
+            '$' => Ok('$'),
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            'u' => self.unicode_escape(col),
+            c => Err(ErrorKind::ScanError(
+                self.line,
+                format!("Invalid escape sequence '\\{}' at column {}.", c, col),
+            )),
+        }
+    }
+
+    /// Decodes the `{XXXX}` part of a `\u{XXXX}` escape starting at `col`.
+    fn unicode_escape(&mut self, col: usize) -> Result<char> {
+        let mut digits = String::new();
+        let closed = if self.match_next('{') {
+            while let Some(c) = self.peek().filter(char::is_ascii_hexdigit) {
+                digits.push(c);
+                self.advance();
+            }
+            self.match_next('}')
+        } else {
+            false
+        };
+        let c = if closed && (1..=6).contains(&digits.len()) {
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+        } else {
+            None
+        };
+        c.ok_or_else(|| {
+            ErrorKind::ScanError(
+                self.line,
+                format!("Invalid unicode escape at column {}.", col),
+            )
+        })
+    }
+
     fn number(&mut self) -> Result<()> {
         while is_digit(self.peek().unwrap_or(' ')) {
             self.advance();
@@ -219,7 +447,7 @@ impl Scanner {
                 self.advance();
             }
         }
-        let s = &self.src[self.start..self.current];
+        let s: String = self.src[self.start..self.current].iter().collect();
         self.add_token(TokenType::Number(s.parse().unwrap()));
         Ok(())
     }
@@ -229,8 +457,8 @@ impl Scanner {
             self.advance();
         }
 
-        let text = &self.src[self.start..self.current];
-        self.add_token(*KEYWORDS.get(text).unwrap_or(&TokenType::Identifier));
+        let text: String = self.src[self.start..self.current].iter().collect();
+        self.add_token(*KEYWORDS.get(&text).unwrap_or(&TokenType::Identifier));
     }
 
     fn error(&self, s: String) -> Result<()> {
@@ -239,15 +467,15 @@ impl Scanner {
 
     fn advance(&mut self) -> char {
         self.current += 1;
-        self.src.chars().nth(self.current - 1).unwrap()
+        self.src[self.current - 1]
     }
 
     fn peek(&self) -> Option<char> {
-        self.src.chars().nth(self.current)
+        self.src.get(self.current).copied()
     }
 
     fn peek_next(&self) -> Option<char> {
-        self.src.chars().nth(self.current + 1)
+        self.src.get(self.current + 1).copied()
     }
 
     fn is_at_end(&self) -> bool {
@@ -264,9 +492,15 @@ impl Scanner {
         }
     }
 
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn add_token(&mut self, ty: TokenType) {
-        let s = self.src[self.start..self.current].to_string();
-        self.tokens.push(Token::new(ty, s, self.line));
+        let s: String = self.src[self.start..self.current].iter().collect();
+        self.tokens
+            .push(Token::new(ty, s, self.start_line, self.start_col));
     }
 }
 
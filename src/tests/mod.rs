@@ -1,20 +1,1206 @@
-use std::{fs::File, io::Read};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::rc::Rc;
+use std::thread;
 
 use glob::glob;
 
-use crate::Interpreter;
+use crate::ast::Value;
+use crate::errors::ErrorKind;
+use crate::scanner::TokenType;
+use crate::{Engine, Interpreter, Output, SharedBuffer};
 
-fn test_loxfile(path: std::path::PathBuf) {
-    println!("Testing: {}", path.display());
-    let mut s = String::new();
-    let mut file = File::open(path).unwrap();
-    file.read_to_string(&mut s).unwrap();
-    Interpreter::new().run(&s).unwrap();
+const LOXFILES: &str = "src/tests/loxfiles";
+
+/// Fixture directories written for other implementations or earlier chapters
+/// of the book (scanner dumps, AST printers, clox limits and benchmarks).
+const SKIPPED_DIRS: &[&str] = &["benchmark", "expressions", "limit", "scanning"];
+
+/// Fixtures whose meaning changed with our extensions to Lox: `{}` is now an
+/// (always truthy) map literal, so some loops never terminate.
+const SKIPPED_FILES: &[&str] = &["for/statement_condition.lox", "for/statement_increment.lox"];
+
+/// Fixtures that are expected to fail for now, one path per line relative to
+/// `LOXFILES`. The suite fails if one of these starts passing so the list
+/// doesn't go stale.
+const KNOWN_FAILURES: &str = include_str!("known_failures.txt");
+
+/// What a fixture expects, collected from its `// expect...` and `Error`
+/// comments.
+#[derive(Debug, Default)]
+struct Expectations {
+    output: Vec<String>,
+    compile_errors: Vec<String>,
+    /// The message and the line it should be reported on
+    runtime_error: Option<(usize, String)>,
+}
+
+impl Expectations {
+    fn parse(src: &str) -> Expectations {
+        let mut expected = Expectations::default();
+        for (i, line) in src.lines().enumerate() {
+            let line_no = i + 1;
+            let comment = match line.find("//") {
+                Some(start) => line[start + 2..].trim_start(),
+                None => continue,
+            };
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expected.output.push(output.to_string());
+            } else if let Some(error) = comment.strip_prefix("expect runtime error: ") {
+                expected.runtime_error = Some((line_no, error.to_string()));
+            } else if let Some(error) = comment.strip_prefix("[java line ") {
+                expected.compile_errors.push(format!("[line {}", error));
+            } else if comment.starts_with("[line ") {
+                expected.compile_errors.push(comment.to_string());
+            } else if comment.starts_with("Error") {
+                expected
+                    .compile_errors
+                    .push(format!("[line {}] {}", line_no, comment));
+            }
+        }
+        expected
+    }
+}
+
+/// Formats compile errors the way the fixtures spell them.
+fn compile_error(err: &ErrorKind) -> Option<String> {
+    match *err {
+        ErrorKind::ScanError(line, ref msg) => Some(format!("[line {}] Error: {}", line, msg)),
+        ErrorKind::ParseError { ref tok, ref t } | ErrorKind::ResolveError { ref tok, ref t } => {
+            let location = if tok.ty == TokenType::Eof {
+                "end".to_string()
+            } else {
+                format!("'{}'", tok.lexeme)
+            };
+            Some(format!("[line {}] Error at {}: {}", tok.line, location, t))
+        }
+        _ => None,
+    }
+}
+
+/// Runs a single fixture, returning a description of every mismatch.
+fn check_loxfile(path: &Path, engine: Engine) -> Vec<String> {
+    let mut src = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut src))
+        .unwrap();
+    let expected = Expectations::parse(&src);
+
+    let buffer = SharedBuffer::new();
+    let res = match panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut interpreter = Interpreter::with_output(buffer.clone());
+        interpreter.set_engine(engine);
+        interpreter.run(&src)
+    })) {
+        Ok(res) => res,
+        Err(_) => return vec!["interpreter panicked".to_string()],
+    };
+    let output = buffer.contents();
+
+    let mut failures = vec![];
+    let output: Vec<&str> = output.lines().collect();
+    if output != expected.output {
+        failures.push(format!(
+            "expected output {:?}, got {:?}",
+            expected.output, output
+        ));
+    }
+
+    let (compile_errors, runtime_error) = match res {
+        Ok(_) => (vec![], None),
+        Err(e) => match e.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::RuntimeError { tok, t, .. }) => (vec![], Some((tok.line, t.clone()))),
+            Some(err) => match compile_error(err) {
+                Some(err) => (vec![err], None),
+                None => (vec![], Some((0, err.to_string()))),
+            },
+            None => (vec![], Some((0, e.to_string()))),
+        },
+    };
+    if compile_errors != expected.compile_errors {
+        failures.push(format!(
+            "expected compile errors {:?}, got {:?}",
+            expected.compile_errors, compile_errors
+        ));
+    }
+    if runtime_error != expected.runtime_error {
+        failures.push(format!(
+            "expected runtime error {:?}, got {:?}",
+            expected.runtime_error, runtime_error
+        ));
+    }
+    failures
+}
+
+fn run_loxfiles(engine: Engine) {
+    let known_failures: HashSet<&str> = KNOWN_FAILURES
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+
+    let mut passed = 0;
+    let mut unexpected = vec![];
+    let mut known = 0;
+    for entry in glob(&format!("{}/**/*.lox", LOXFILES)).unwrap() {
+        let path = entry.unwrap();
+        let name = path
+            .strip_prefix(LOXFILES)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        if SKIPPED_DIRS
+            .iter()
+            .any(|dir| name.starts_with(&format!("{}/", dir)))
+            || SKIPPED_FILES.contains(&name.as_str())
+        {
+            continue;
+        }
+
+        let failures = check_loxfile(&path, engine);
+        let is_known = known_failures.contains(name.as_str());
+        match (failures.is_empty(), is_known) {
+            (true, false) => {
+                println!("PASS {}", name);
+                passed += 1;
+            }
+            (true, true) => {
+                println!("FAIL {}: passes but is listed as a known failure", name);
+                unexpected.push(name);
+            }
+            (false, true) => {
+                println!("XFAIL {}", name);
+                known += 1;
+            }
+            (false, false) => {
+                println!("FAIL {}", name);
+                for failure in failures {
+                    println!("    {}", failure);
+                }
+                unexpected.push(name);
+            }
+        }
+    }
+
+    println!(
+        "{} passed, {} failed, {} known failures",
+        passed,
+        unexpected.len(),
+        known
+    );
+    assert!(unexpected.is_empty(), "Failing loxfiles: {:?}", unexpected);
 }
 
 #[test]
 fn test_loxfiles() {
-    for entry in glob("./src/tests/loxfiles/**/*.lox").unwrap() {
-        test_loxfile(entry.unwrap());
+    // Deeply recursive fixtures need more stack than the default test thread
+    thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(|| run_loxfiles(Engine::TreeWalker))
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn test_loxfiles_vm() {
+    run_loxfiles(Engine::Vm);
+}
+
+#[test]
+fn test_output_sinks() {
+    let buffer = SharedBuffer::new();
+    let lines = Rc::new(RefCell::new(vec![]));
+    let mut first = Interpreter::with_output(buffer.clone());
+    let mut second = Interpreter::with_output(Output::callback({
+        let lines = lines.clone();
+        move |line: &str| lines.borrow_mut().push(line.to_string())
+    }));
+
+    first.run("print \"one\"; print 1 + 1;").unwrap();
+    second.run("print \"two\"; print nil;").unwrap();
+
+    assert_eq!(buffer.contents(), "one\n2\n");
+    assert_eq!(*lines.borrow(), vec!["two", "nil"]);
+
+    // Plain writers convert without going through `Output::writer`
+    let path = std::env::temp_dir().join(format!("rlox-output-{}.txt", std::process::id()));
+    let mut third = Interpreter::with_output(File::create(&path).unwrap());
+    third.run("print \"three\";").unwrap();
+    drop(third);
+    let mut written = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut written))
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(written, "three\n");
+
+    // Switching sinks sends later output only to the new one
+    let later = SharedBuffer::new();
+    let mut fourth = Interpreter::with_output(Vec::new());
+    fourth.run("print 4;").unwrap();
+    fourth.set_output(later.clone());
+    fourth.run("print 5;").unwrap();
+    assert_eq!(later.contents(), "5\n");
+}
+
+#[test]
+fn test_runtime_error_trace() {
+    let src = "fun inner(x) {\n  return -x;\n}\nfun outer() {\n  inner(\"s\");\n}\nouter();\n";
+    for engine in [Engine::TreeWalker, Engine::Vm] {
+        let mut interpreter = Interpreter::with_output(SharedBuffer::new());
+        interpreter.set_engine(engine);
+        let err = interpreter.run(src).unwrap_err();
+        match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::RuntimeError { tok, trace, .. }) => {
+                assert_eq!((tok.line, tok.col), (2, 10));
+                let trace: Vec<String> = trace.iter().map(|frame| frame.to_string()).collect();
+                assert_eq!(
+                    trace,
+                    vec![
+                        "[line 2] in inner()",
+                        "[line 5] in outer()",
+                        "[line 7] in script"
+                    ]
+                );
+            }
+            _ => panic!("expected a runtime error from {:?}, got {}", engine, err),
+        }
+    }
+}
+
+#[test]
+fn test_multiline_string_position() {
+    let err = Interpreter::with_output(SharedBuffer::new())
+        .run("print 1\n  \"a\nb\";")
+        .unwrap_err();
+    match err.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::ParseError { tok, .. }) => assert_eq!((tok.line, tok.col), (2, 3)),
+        _ => panic!("expected a parse error, got {}", err),
+    }
+}
+
+/// Runs `src` on both engines, checking that they print the same thing, and
+/// returns the output.
+fn run_output(src: &str) -> String {
+    let outputs: Vec<String> = [Engine::TreeWalker, Engine::Vm]
+        .into_iter()
+        .map(|engine| {
+            let buffer = SharedBuffer::new();
+            let mut interpreter = Interpreter::with_output(buffer.clone());
+            interpreter.set_engine(engine);
+            if let Err(e) = interpreter.run(src) {
+                panic!("{:?} failed: {}", engine, e);
+            }
+            buffer.contents()
+        })
+        .collect();
+    assert_eq!(outputs[0], outputs[1], "engines disagree");
+    outputs[0].clone()
+}
+
+#[test]
+fn test_return_value_does_not_leak() {
+    let src = "fun f() { 1; }\nprint f();\nfun g() { while (true) { return 2; } }\nprint g();\n";
+    assert_eq!(run_output(src), "nil\n2\n");
+}
+
+#[test]
+fn test_break_and_continue() {
+    let src = "
+        for (var i = 0; i < 10; i = i + 1) {
+            if (i == 1) continue;
+            if (i == 4) break;
+            var doubled = i * 2;
+            fun show() { print doubled; }
+            show();
+        }
+        var n = 0;
+        for (;;) { n = n + 1; if (n > 2) break; }
+        print n;
+        while (true) { { var x = 1; break; } }
+    ";
+    assert_eq!(run_output(src), "0\n4\n6\n3\n");
+
+    for src in [
+        "break;",
+        "fun f() { while (true) { fun g() { continue; } } }",
+    ] {
+        let err = Interpreter::with_output(SharedBuffer::new())
+            .run(src)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("outside of a loop"),
+            "unexpected error {}",
+            err
+        );
+    }
+}
+
+/// Runs `src` on both engines, checking that they fail with the same runtime
+/// error, and returns its message.
+fn run_error(src: &str) -> String {
+    let errors: Vec<String> = [Engine::TreeWalker, Engine::Vm]
+        .into_iter()
+        .map(|engine| {
+            let mut interpreter = Interpreter::with_output(SharedBuffer::new());
+            interpreter.set_engine(engine);
+            let err = interpreter.run(src).unwrap_err();
+            match err.downcast_ref::<ErrorKind>() {
+                Some(ErrorKind::RuntimeError { t, .. }) => t.clone(),
+                _ => panic!("expected a runtime error from {:?}, got {}", engine, err),
+            }
+        })
+        .collect();
+    assert_eq!(errors[0], errors[1], "engines disagree");
+    errors[0].clone()
+}
+
+#[test]
+fn test_lists() {
+    let src = "
+        var xs = [1, \"two\", [3]];
+        print xs;
+        xs[0] = xs[0] + 1;
+        xs.push(4);
+        print xs[0];
+        print xs.len();
+        print xs.pop();
+        xs.insert(0, nil);
+        print xs.remove(1);
+        print xs.slice(1, 3);
+        xs.reverse();
+        print xs;
+        var alias = xs;
+        alias.push(5);
+        print xs.len();
+    ";
+    assert_eq!(
+        run_output(src),
+        "[1, \"two\", [3]]\n2\n4\n4\n2\n[\"two\", [3]]\n[[3], \"two\", nil]\n4\n"
+    );
+    assert_eq!(
+        run_output("var xs = [1]; xs.push(xs); print xs; print [xs, xs];"),
+        "[1, [...]]\n[[1, [...]], [1, [...]]]\n"
+    );
+
+    assert_eq!(
+        run_error("[1, 2][2];"),
+        "List index 2 is out of bounds for length 2."
+    );
+    assert_eq!(
+        run_error("var xs = []; xs[0.5] = 1;"),
+        "List index must be an integer, got 0.5."
+    );
+    assert_eq!(run_error("[].pop();"), "Can't pop from an empty list.");
+    assert_eq!(run_error("[].nope;"), "Undefined property 'nope'.");
+}
+
+#[test]
+fn test_maps() {
+    let src = "
+        var m = {\"a\": 1, 2: \"two\", true: nil,};
+        print m;
+        m[\"b\"] = m[\"a\"] + 1;
+        m[-0] = \"zero\";
+        print m[0];
+        print m.has(\"b\");
+        print m.delete(\"a\");
+        print m.has(\"a\");
+        print m.keys();
+        print m.values();
+        print m.size();
+    ";
+    assert_eq!(
+        run_output(src),
+        "{\"a\": 1, 2: \"two\", true: nil}\nzero\ntrue\n1\nfalse\n\
+         [2, true, \"b\", 0]\n[\"two\", nil, 2, \"zero\"]\n4\n"
+    );
+    assert_eq!(
+        run_output("var m = {}; m[\"self\"] = m; m[\"list\"] = [m]; print m;"),
+        "{\"self\": {...}, \"list\": [{...}]}\n"
+    );
+
+    assert_eq!(run_error("print {}[\"x\"];"), "Map has no key \"x\".");
+    assert_eq!(
+        run_error("fun f() {} var m = {}; m[f] = 1;"),
+        "<fn f> can't be used as a map key; only strings, numbers and booleans can."
+    );
+    assert_eq!(
+        run_error("var m = {}; m[0/0] = 1;"),
+        "NaN can't be used as a map key."
+    );
+    assert_eq!(
+        run_error("print {0/0: 1};"),
+        "NaN can't be used as a map key."
+    );
+    assert_eq!(
+        run_error("print {[]: 1};"),
+        "[] can't be used as a map key; only strings, numbers and booleans can."
+    );
+}
+
+#[test]
+fn test_string_escapes() {
+    let src = r#"print "\"q\" \\ a\tb\r\0 \u{e9}\u{1F600}"; print "x\ny";"#;
+    assert_eq!(run_output(src), "\"q\" \\ a\tb\r\0 é😀\nx\ny\n");
+
+    for (src, message) in [
+        (
+            "print 1;\nprint \"a\\qb\";",
+            (2, "Invalid escape sequence '\\q' at column 9."),
+        ),
+        (
+            "print \"\\u{110000}\";",
+            (1, "Invalid unicode escape at column 8."),
+        ),
+        (
+            "print \"\\u00e9\";",
+            (1, "Invalid unicode escape at column 8."),
+        ),
+    ] {
+        let err = Interpreter::with_output(SharedBuffer::new())
+            .run(src)
+            .unwrap_err();
+        match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::ScanError(line, t)) => assert_eq!((*line, t.as_str()), message),
+            _ => panic!("expected a scan error, got {}", err),
+        }
+    }
+}
+
+#[test]
+fn test_string_interpolation() {
+    let src = r#"
+        var a = 2;
+        var m = {"k": "v"};
+        print "total: ${a + 3} items";
+        print "${m["k"]} ${ {"x": [a]}["x"] } ${"inner ${a}"}";
+        print "${nil}${"s"}${1.5}";
+        print "\${a}";
+    "#;
+    assert_eq!(
+        run_output(src),
+        "total: 5 items\nv [2] inner 2\nnils1.5\n${a}\n"
+    );
+
+    let err = Interpreter::with_output(SharedBuffer::new())
+        .run("print \"${1 + 2\";")
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ErrorKind>(),
+        Some(ErrorKind::ScanError(..))
+    ));
+
+    let err = Interpreter::with_output(SharedBuffer::new())
+        .run("print \"x${}y\";")
+        .unwrap_err();
+    match err.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::ParseError { tok, t }) => {
+            assert_eq!(tok.ty, TokenType::Interpolation);
+            assert_eq!(t, "Expect expression in interpolation.");
+        }
+        _ => panic!("expected a parse error, got {}", err),
+    }
+}
+
+#[test]
+fn test_arithmetic_and_bitwise_operators() {
+    let src = "
+        print 7 % 3;
+        print -7 % 3;
+        print 7 ~/ 2;
+        print -7 ~/ 2;
+        print 2 ** 3 ** 2;
+        print -2 ** 2;
+        print 2 ** -1;
+        print 6 & 3 | 8 ^ 1;
+        print 1 << 4 >> 2;
+        print ~5;
+        print 1 + 2 << 1;
+        print 5 & 4 == 4;
+    ";
+    assert_eq!(
+        run_output(src),
+        "1\n2\n3\n-4\n512\n-4\n0.5\n11\n4\n-6\n6\ntrue\n"
+    );
+    assert_eq!(
+        run_error("print 1.5 & 1;"),
+        "Expected an integer, instead got: 1.5"
+    );
+    assert_eq!(
+        run_error("print ~\"a\";"),
+        "Expected an integer, instead got: \"a\""
+    );
+    assert_eq!(
+        run_error("print 1 << 64;"),
+        "Shift amount must be between 0 and 63, instead got: 64"
+    );
+    assert_eq!(
+        run_error("print \"a\" % 2;"),
+        "Expected a number, instead got: \"a\""
+    );
+}
+
+#[test]
+fn test_compound_assignment_and_increments() {
+    let src = "
+        var i = 1;
+        i += 2;
+        i *= 5;
+        i -= 1;
+        i /= 2;
+        i %= 4;
+        print i;
+        print i++;
+        print i;
+        print ++i;
+        print i--;
+        print --i;
+        var s = \"a\";
+        s += \"b\";
+        print s;
+
+        class Counter {}
+        var c = Counter();
+        c.n = 10;
+        print c.n++;
+        print c.n += 5;
+        print --c.n;
+
+        var calls = 0;
+        fun pick(xs) {
+            calls++;
+            return xs;
+        }
+        var xs = [1, 2];
+        print pick(xs)[calls]++;
+        print pick(xs)[0] += 10;
+        print xs;
+        print calls;
+
+        fun counter() {
+            var n = 0;
+            fun next() {
+                return n++;
+            }
+            return next;
+        }
+        var next = counter();
+        next();
+        print next();
+    ";
+    assert_eq!(
+        run_output(src),
+        "3\n3\n4\n5\n5\n3\nab\n10\n16\n15\n2\n11\n[11, 3]\n2\n1\n"
+    );
+    // Without an assignable target `--` is still two negations
+    assert_eq!(
+        run_output("var k = 2; print --(3); print ---k; print --(k + 1); print k;"),
+        "3\n-2\n3\n2\n"
+    );
+    assert_eq!(
+        run_error("var s = \"a\"; s--;"),
+        "Expected a number, instead got: \"a\""
+    );
+
+    let err = Interpreter::with_output(SharedBuffer::new())
+        .run("var a = 1; (a)++;")
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ErrorKind>(),
+        Some(ErrorKind::ParseError { .. })
+    ));
+}
+
+#[test]
+fn test_conditional_coalesce_and_optional_chaining() {
+    let src = "
+        print true ? 1 : 2;
+        print nil ? 1 : false ? 2 : 3;
+        var x = 5;
+        print x > 3 ? \"big\" : \"small\";
+        print false or nil ? \"yes\" : \"no\";
+        x = true ? 7 : 8;
+        print x;
+
+        fun boom() {
+            print \"evaluated\";
+            return 0;
+        }
+        print nil ?? \"default\";
+        print false ?? boom();
+        print nil ?? nil ?? 3;
+
+        class Node {
+            init(value, next) {
+                this.value = value;
+                this.next = next;
+            }
+            describe() {
+                return \"node \" + this.value;
+            }
+        }
+        var list = Node(1, Node(2, nil));
+        print list?.next?.value;
+        print list.next.next?.value;
+        print list.next.next?.next.value;
+        print list?.describe();
+        var missing;
+        print missing?.describe(boom());
+        print missing?.value ?? \"none\";
+    ";
+    assert_eq!(
+        run_output(src),
+        "1\n3\nbig\nno\n7\ndefault\nfalse\n3\n2\nnil\nnil\nnode 1\nnil\nnone\n"
+    );
+    assert_eq!(run_error("print 1?.x;"), "Only instances have properties.");
+}
+
+#[test]
+fn test_function_expressions() {
+    let src = "
+        fun apply(f, x) {
+            return f(x);
+        }
+        print apply(fun (n) { return n * 2; }, 4);
+        print apply((n) => n + 1, 4);
+        var add = (a, b) => a + b;
+        print add(2, 3);
+        var hello = () => \"hi\";
+        print hello();
+        print add;
+        print fun () {};
+
+        fun counter() {
+            var n = 0;
+            return () => {
+                n += 1;
+                return n;
+            };
+        }
+        var next = counter();
+        next();
+        print next();
+
+        var xs = [1, 2, 3];
+        var doubled = [];
+        for (var i = 0; i < xs.len(); i++) {
+            var f = (x) => x * 2;
+            doubled.push(f(xs[i]));
+        }
+        print doubled;
+        print (1 + 2) * 3;
+        fun (x) { print x; }(\"called\");
+    ";
+    assert_eq!(
+        run_output(src),
+        "8\n5\n5\nhi\n<fn anonymous>\n<fn anonymous>\n2\n[2, 4, 6]\n9\ncalled\n"
+    );
+
+    let err = Interpreter::with_output(SharedBuffer::new())
+        .run("while (true) { var f = () => { break; }; }")
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ErrorKind>(),
+        Some(ErrorKind::ParseError { .. })
+    ));
+}
+
+#[test]
+fn test_exceptions() {
+    let src = r#"
+        fun risky(n) {
+            if (n > 2) throw "too big: " + n;
+            return n;
+        }
+        try {
+            print risky(1);
+            print risky(5);
+            print "unreachable";
+        } catch (e) {
+            print "caught " + e;
+        }
+
+        try {
+            print nil + 1;
+        } catch (e) {
+            print e.message;
+            print e.line;
+        }
+        fun arity(a) {}
+        try { arity(); } catch (e) { print e.message; }
+        try { undefinedThing; } catch (e) { print e.message; }
+
+        fun order() {
+            try {
+                print "body";
+                return "returned";
+            } finally {
+                print "cleanup";
+            }
+        }
+        print order();
+        fun override() {
+            try { throw "lost"; } finally { return "finally wins"; }
+        }
+        print override();
+
+        try {
+            try { throw "inner"; } finally { print "inner finally"; }
+        } catch (e) {
+            print "outer caught " + e;
+        }
+
+        for (var i = 0; i < 3; i++) {
+            try {
+                if (i == 1) continue;
+                if (i == 2) break;
+                print i;
+            } finally {
+                print "f${i}";
+            }
+        }
+
+        fun shadow() {
+            var x = "function";
+            while (true) {
+                try {
+                    var x = "inner";
+                    break;
+                } finally {
+                    print x;
+                }
+            }
+        }
+        shadow();
+
+        try {
+            try { throw "P"; } finally {
+                try {
+                    try { throw "Q"; } finally { throw "R"; }
+                } catch (e) {
+                    print "caught " + e;
+                }
+            }
+        } catch (e) {
+            print "outer " + e;
+        }
+
+        fun deep(n) {
+            if (n == 0) throw [1, 2];
+            return deep(n - 1);
+        }
+        try { deep(5); } catch (e) { print e; }
+    "#;
+    assert_eq!(
+        run_output(src),
+        "1\ncaught too big: 5\nCan't add nil and 1\n15\n\
+         Wrong number of arguments: Expected 1, got 0\n\
+         Undefined variable: undefinedThing\nbody\ncleanup\nreturned\nfinally wins\n\
+         inner finally\nouter caught inner\n0\nf0\nf1\nf2\nfunction\ncaught R\nouter P\n\
+         [1, 2]\n"
+    );
+
+    assert_eq!(
+        run_error("fun f() { throw \"boom\"; } f();"),
+        "Uncaught exception: boom"
+    );
+    assert_eq!(
+        run_error("try { nil(); } catch (e) { throw e; }"),
+        "Uncaught exception: nil is not a valid function"
+    );
+    assert_eq!(
+        run_error("try { nil + 1; } finally { var x = 1; }"),
+        "Can't add nil and 1"
+    );
+    // A caught exception doesn't leak into later runs
+    for engine in [Engine::TreeWalker, Engine::Vm] {
+        let mut interpreter = Interpreter::with_output(SharedBuffer::new());
+        interpreter.set_engine(engine);
+        assert!(interpreter.run("throw 1;").is_err());
+        let value = interpreter
+            .run("var r; try { nil + 1; } catch (e) { r = e.message; } r;")
+            .unwrap();
+        assert_eq!(value, Value::String("Can't add nil and 1".to_string()));
+    }
+}
+
+#[test]
+fn test_modules() {
+    let root = std::env::temp_dir().join(format!("rlox-modules-{}", std::process::id()));
+    let files = [
+        (
+            "main.lox",
+            "import \"util.lox\" as u;
+            import { add, counter } from \"util.lox\";
+            print u;
+            print add(u.add(1, 2), 3);
+            u.bump();
+            print u.counter;
+            print counter;
+            import \"lib/deep.lox\" as d;
+            print d.value;
+            import \"found.lox\" as f;
+            print f.where;
+            try { import \"cycle_a.lox\"; } catch (e) { print e.message; }
+            try { import { missing } from \"util.lox\"; } catch (e) { print e.message; }
+            try { import \"nowhere.lox\" as n; } catch (e) { print e.message; }
+            try { print u.clock; } catch (e) { print e.message; }
+            print u.range;
+            try { u.counter = 5; } catch (e) { print e.message; }
+            ",
+        ),
+        (
+            "util.lox",
+            "print \"loading util\";
+            var counter = 0;
+            fun add(a, b) { return a + b; }
+            fun bump() { counter = counter + 1; }
+            var range = range;",
+        ),
+        (
+            "lib/deep.lox",
+            "import { add } from \"../util.lox\"; var value = add(10, 20);",
+        ),
+        ("cycle_a.lox", "import \"cycle_b.lox\";"),
+        ("cycle_b.lox", "import \"cycle_a.lox\";"),
+        ("search/found.lox", "var where = \"search path\";"),
+    ];
+    for (name, src) in files {
+        let path = root.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, src).unwrap();
+    }
+
+    for engine in [Engine::TreeWalker, Engine::Vm] {
+        let buffer = SharedBuffer::new();
+        let mut interpreter = Interpreter::with_output(buffer.clone());
+        interpreter.set_engine(engine);
+        interpreter.set_search_path(vec![root.join("search")]);
+        if let Err(e) = interpreter.run_path(root.join("main.lox")) {
+            panic!("{:?} failed: {}", engine, e);
+        }
+        assert_eq!(
+            buffer.contents(),
+            "loading util\n<module util.lox>\n6\n1\n0\n30\nsearch path\n\
+             Import cycle: cycle_a.lox -> cycle_b.lox -> cycle_a.lox.\n\
+             Undefined property 'missing'.\n\
+             Can't find module \"nowhere.lox\".\n\
+             Undefined property 'clock'.\n\
+             <built-in function range>\n\
+             Can't assign to module bindings.\n",
+            "{:?}",
+            engine
+        );
+    }
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_for_in() {
+    let src = "
+        for (x in [1, 2]) print x;
+        for (var key in {\"a\": 1, \"b\": 2}) print key;
+        for (c in \"hi\") print c;
+        for (i in range(0, 10)) {
+            if (i == 1) continue;
+            if (i == 3) break;
+            print i;
+        }
+        var fns = [];
+        for (i in range(0, 3)) fns.push(fun() { return i; });
+        for (f in fns) print f();
+
+        class Countdown {
+            init(n) { this.n = n; }
+            iter() { return CountdownIter(this.n); }
+        }
+        class CountdownIter {
+            init(n) { this.n = n; this.done = n <= 0; }
+            next() {
+                this.n--;
+                this.done = this.n <= 0;
+                return this.n + 1;
+            }
+        }
+        for (n in Countdown(2)) print n;
+
+        fun first(xs) {
+            for (x in xs) try { return x; } finally { print \"finally\"; }
+        }
+        print first([\"first\", \"second\"]);
+    ";
+    assert_eq!(
+        run_output(src),
+        "1\n2\na\nb\nh\ni\n0\n2\n0\n1\n2\n2\n1\nfinally\nfirst\n"
+    );
+    assert_eq!(
+        run_error("for (x in 1) print x;"),
+        "Can only iterate over lists, maps, strings, ranges, generators and objects with an iter() method, instead got: 1"
+    );
+    assert_eq!(
+        run_error("class A { iter() { return this; } }\nfor (x in A()) print x;"),
+        "Undefined property 'done'."
+    );
+}
+
+#[test]
+fn test_generators() {
+    let src = "
+        fun* count(n) {
+            print \"start\";
+            for (var i = 0; i < n; i++) yield i;
+        }
+        var g = count(2);
+        print \"created\";
+        print g.next();
+        print g.done;
+        print g.next();
+        print g.done;
+
+        fun* naturals() { var i = 0; while (true) yield i++; }
+        fun* map(f, it) { for (x in it) yield f(x); }
+        fun* take(n, it) {
+            if (n <= 0) return;
+            for (x in it) {
+                yield x;
+                if (--n == 0) return;
+            }
+        }
+        for (x in take(3, map((x) => x * x, naturals()))) print x;
+
+        fun* captures() {
+            var n = 0;
+            yield fun() { return n++; };
+            yield n;
+        }
+        var c = captures();
+        var inc = c.next();
+        inc();
+        print c.next();
+
+        fun* guarded() {
+            try {
+                yield 1;
+                throw \"boom\";
+            } catch (e) {
+                yield \"caught \" + e;
+            } finally {
+                yield \"finally\";
+            }
+        }
+        for (x in guarded()) print x;
+
+        class Bag {
+            init(items) { this.items = items; }
+            *iter() { for (x in this.items) if (x != nil) yield x; }
+        }
+        for (x in Bag([\"a\", nil, \"b\"])) print x;
+        print fun*() {}().done;
+    ";
+    assert_eq!(
+        run_output(src),
+        "created\nstart\n0\nfalse\n1\ntrue\n0\n1\n4\n1\n1\ncaught boom\nfinally\na\nb\ntrue\n"
+    );
+    assert_eq!(
+        run_error("fun* f() { yield 1; }\nvar g = f();\ng.next();\ng.next();"),
+        "Iterator is exhausted."
+    );
+    assert_eq!(
+        run_error("var g;\nfun* f() { g.next(); }\ng = f();\ng.next();"),
+        "Generator is already running."
+    );
+}
+
+#[test]
+fn test_constants() {
+    let src = "
+        const limit = 3;
+        fun scaled() { const scale = 2; return limit * scale; }
+        print scaled();
+        { var limit = 1; limit += 4; print limit; }
+        const items = [1];
+        items[0] = 5;
+        print items;
+        print limit;
+    ";
+    assert_eq!(run_output(src), "6\n5\n[5]\n3\n");
+
+    for (src, message) in [
+        ("const x = 1;\nx = 2;", (2, "Can't assign to constant 'x'.")),
+        (
+            "const x = 1;\nfun f() { x++; }",
+            (2, "Can't assign to constant 'x'."),
+        ),
+        (
+            "{\n  const x = 1;\n  x += 1;\n}",
+            (3, "Can't assign to constant 'x'."),
+        ),
+        (
+            "const x = 1;\nvar x = 2;",
+            (2, "Can't redeclare constant 'x'."),
+        ),
+        (
+            "const x = 1;\nclass x {}",
+            (2, "Can't redeclare constant 'x'."),
+        ),
+    ] {
+        let err = Interpreter::with_output(SharedBuffer::new())
+            .run(src)
+            .unwrap_err();
+        match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::ResolveError { tok, t }) => {
+                assert_eq!((tok.line, t.as_str()), message)
+            }
+            _ => panic!("expected a resolve error, got {}", err),
+        }
+    }
+
+    // Assignments the resolver can't see coming fail when they run
+    assert_eq!(
+        run_error("fun set() { x = 2; }\nconst x = 1;\nset();"),
+        "Can't assign to constant 'x'."
+    );
+    for engine in [Engine::TreeWalker, Engine::Vm] {
+        let buffer = SharedBuffer::new();
+        let mut interpreter = Interpreter::with_output(buffer.clone());
+        interpreter.set_engine(engine);
+        interpreter.run("const x = 1;").unwrap();
+        for (src, message) in [
+            ("x = 2;", "Can't assign to constant 'x'."),
+            ("var x = 2;", "Can't redeclare constant 'x'."),
+            ("fun x() {}", "Can't redeclare constant 'x'."),
+        ] {
+            let err = interpreter.run(src).unwrap_err();
+            match err.downcast_ref::<ErrorKind>() {
+                Some(ErrorKind::RuntimeError { t, .. }) => assert_eq!(t, message),
+                _ => panic!("expected a runtime error from {:?}, got {}", engine, err),
+            }
+        }
+        interpreter.run("print x;").unwrap();
+        assert_eq!(buffer.contents(), "1\n");
+    }
+}
+
+#[test]
+fn test_default_rest_and_spread_params() {
+    let src = "
+        fun greet(name, greeting = \"Hello\", end = greeting == \"Hello\" ? \"!\" : \".\") {
+            print greeting + \", \" + name + end;
+        }
+        greet(\"Ann\");
+        greet(\"Bob\", \"Hi\");
+        greet(\"Cy\", \"Yo\", \"?\");
+
+        var calls = 0;
+        fun next(id = calls++) { return id; }
+        print next();
+        print next(10);
+        print next();
+
+        fun sum(first, ...rest) {
+            for (x in rest) first += x;
+            return first;
+        }
+        var xs = [2, 3];
+        print sum(1);
+        print sum(...xs);
+        print sum(1, ...xs, 4, ...[5]);
+
+        class Point { init(x = 0, y = x) { this.x = x; this.y = y; } }
+        var p = Point(2);
+        print p.y;
+        print ((a, b = 1) => a + b)(1);
+        fun* repeat(value, times = 2) { for (i in range(0, times)) yield value; }
+        for (x in repeat(\"r\")) print x;
+    ";
+    assert_eq!(
+        run_output(src),
+        "Hello, Ann!\nHi, Bob.\nYo, Cy?\n0\n10\n1\n1\n5\n15\n2\n2\nr\nr\n"
+    );
+
+    assert_eq!(
+        run_error("fun f(a, b = 1) {}\nf();"),
+        "Wrong number of arguments: Expected 1 to 2, got 0"
+    );
+    assert_eq!(
+        run_error("fun f(a, ...rest) {}\nf();"),
+        "Wrong number of arguments: Expected at least 1, got 0"
+    );
+    assert_eq!(
+        run_error("fun f(a) {}\nf(...[1, 2]);"),
+        "Wrong number of arguments: Expected 1, got 2"
+    );
+    assert_eq!(
+        run_error("fun f(a) {}\nf(...1);"),
+        "Can only spread lists, instead got: 1"
+    );
+}
+
+#[test]
+fn test_named_arguments() {
+    let src = "
+        fun connect(host, port = 80, secure = port == 443) {
+            print \"${host}:${port} ${secure}\";
+        }
+        connect(host: \"a\", port: 8080);
+        connect(\"b\", secure: true);
+        connect(port: 443, host: \"c\");
+
+        fun label(a = \"A\", b = \"B\", c = a + b) { print a + b + c; }
+        label(b: \"x\");
+        fun pair(first, second = 0, ...rest) { print [first, second, rest]; }
+        pair(...[1], second: 2);
+
+        class Point { init(x, y = 0) { this.sum = x + y; } }
+        print Point(y: 2, x: 1).sum;
+        fun* count(from = 0, to = from + 2) { for (i in range(from, to)) yield i; }
+        for (i in count(to: 2)) print i;
+        print range(end: 3, start: 2).next();
+        var xs = [1];
+        xs.insert(item: 0, index: 0);
+        print xs;
+    ";
+    assert_eq!(
+        run_output(src),
+        "a:8080 false\nb:80 true\nc:443 true\nAxAx\n[1, 2, []]\n3\n0\n1\n2\n[0, 1]\n"
+    );
+
+    let define = "fun f(a, b = 1) {}\n";
+    for (call, message) in [
+        ("f(c: 1);", "No parameter named 'c'."),
+        (
+            "f(1, a: 2);",
+            "Got more than one argument for parameter 'a'.",
+        ),
+        ("f(b: 2);", "Missing argument for parameter 'a'."),
+        (
+            "f(1, 2, 3, b: 4);",
+            "Wrong number of arguments: Expected 1 to 2, got 4",
+        ),
+    ] {
+        assert_eq!(run_error(&format!("{}{}", define, call)), message);
+    }
+    assert_eq!(run_error("clock(x: 1);"), "No parameter named 'x'.");
+
+    for (src, message) in [
+        ("f(a: 1, a: 2);", "Can't pass argument 'a' twice."),
+        (
+            "f(a: 1, 2);",
+            "Can't pass a positional argument after a named one.",
+        ),
+    ] {
+        let err = Interpreter::with_output(SharedBuffer::new())
+            .run(src)
+            .unwrap_err();
+        match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::ParseError { t, .. }) => assert_eq!(t, message),
+            _ => panic!("expected a parse error, got {}", err),
+        }
     }
 }
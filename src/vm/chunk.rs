@@ -0,0 +1,145 @@
+use std::rc::Rc;
+
+use crate::ast::{BinaryOperator, UnaryOperator, Value};
+use crate::scanner::Token;
+use crate::vm::object::FunctionProto;
+
+/// A single VM instruction. Operands index into the owning `Chunk`'s tables,
+/// into the current frame's stack slots, or into its code for jumps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Constant(usize),
+    Nil,
+    True,
+    False,
+    Pop,
+    /// Pushes a copy of the value the given distance below the top
+    Dup(usize),
+    /// Moves the top of the stack below the given number of values under it
+    Bury(usize),
+    GetLocal(usize),
+    SetLocal(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    GetGlobal(usize),
+    DefineGlobal(usize),
+    /// Defines a global that can't be assigned to or redeclared
+    DefineConstant(usize),
+    SetGlobal(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    /// Pops the superclass and the instance and pushes the bound method
+    GetSuper(usize),
+    /// Builds a list from the given number of items on top of the stack
+    List(usize),
+    /// Stringifies and concatenates the given number of values
+    Interpolate(usize),
+    /// Builds a map from the given number of key-value pairs on the stack
+    Map(usize),
+    GetIndex,
+    SetIndex,
+    Unary(UnaryOperator),
+    Binary(BinaryOperator),
+    Print,
+    Jump(usize),
+    /// Jumps if the top of the stack is falsey, without popping it
+    JumpIfFalse(usize),
+    /// Jumps if the top of the stack is truthy, without popping it
+    JumpIfTrue(usize),
+    /// Jumps if the top of the stack is nil, without popping it
+    JumpIfNil(usize),
+    /// Jumps if the top of the stack isn't nil, without popping it
+    JumpIfNotNil(usize),
+    /// Jumps if the given parameter got an argument, skipping the code
+    /// computing its default
+    JumpIfGiven(usize, usize),
+    /// Calls the value below the given number of arguments
+    Call(usize),
+    /// Like `Call`, with one more argument after the others for each of the
+    /// names in the given entry of the chunk's argument names
+    CallNamed(usize, usize),
+    /// Replaces the list on top of the stack with a copy, so it can be
+    /// spread into a call's arguments
+    Spread,
+    /// Calls the value below the given number of lists, with their items as
+    /// the positional arguments, and any named arguments after them
+    CallSpread(usize, Option<usize>),
+    Closure(usize),
+    CloseUpvalue,
+    Return,
+    /// Suspends the running generator, handing its caller the value on top
+    /// of the stack
+    Yield,
+    /// Installs a handler at the given target for exceptions raised before
+    /// the matching `PopTry`, which starts with the caught value pushed
+    Try(usize),
+    /// Installs a handler at the given target that runs a `finally` block
+    /// for any error and then rethrows it. It starts with a reference to the
+    /// error pushed, for `Rethrow`.
+    TryFinally(usize),
+    PopTry,
+    /// Throws the value on top of the stack
+    Throw,
+    /// Raises the error a `TryFinally` handler set aside again
+    Rethrow,
+    /// Checks that the top of the stack can be inherited from
+    Superclass,
+    /// Builds a class from its name, the given number of method closures on
+    /// top of the stack and, if the flag is set, the superclass below them.
+    Class(usize, usize, bool),
+    /// Replaces the value on top of the stack with its `iter()` method, to
+    /// start a `for-in` loop
+    Iter,
+    /// Pushes the module whose path is the given name, running it the first
+    /// time it's imported
+    Import(usize),
+}
+
+/// Compiled code for one function.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    /// The source token each instruction was compiled from, for errors
+    pub tokens: Vec<Rc<Token>>,
+    pub constants: Vec<Value>,
+    /// Variable and property names
+    pub names: Vec<String>,
+    /// Functions declared directly inside this one
+    pub functions: Vec<Rc<FunctionProto>>,
+    /// The names of each call's named arguments
+    pub arg_names: Vec<Vec<String>>,
+}
+
+impl Chunk {
+    /// Appends `op` and returns its index.
+    pub fn write(&mut self, op: Op, tok: Rc<Token>) -> usize {
+        self.code.push(op);
+        self.tokens.push(tok);
+        self.code.len() - 1
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    pub fn add_name(&mut self, name: &str) -> usize {
+        match self.names.iter().position(|n| n == name) {
+            Some(i) => i,
+            None => {
+                self.names.push(name.to_owned());
+                self.names.len() - 1
+            }
+        }
+    }
+
+    pub fn add_arg_names(&mut self, names: Vec<String>) -> usize {
+        self.arg_names.push(names);
+        self.arg_names.len() - 1
+    }
+
+    pub fn add_function(&mut self, function: FunctionProto) -> usize {
+        self.functions.push(Rc::new(function));
+        self.functions.len() - 1
+    }
+}
@@ -0,0 +1,906 @@
+use std::mem;
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::scanner::{Token, TokenType};
+use crate::vm::chunk::{Chunk, Op};
+use crate::vm::object::{FunctionProto, UpvalueDesc};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Debug)]
+struct Local {
+    name: String,
+    depth: usize,
+    is_captured: bool,
+}
+
+/// A loop being compiled, with the jumps that leave it still to be patched.
+#[derive(Debug)]
+struct Loop {
+    /// Scope depth outside the loop body
+    scope_depth: usize,
+    /// Number of `try` statements the loop is inside of
+    tries: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+/// A `try` statement whose handler is installed in the code being compiled.
+#[derive(Debug)]
+struct TryBlock {
+    /// Number of locals declared when the statement started
+    locals: usize,
+    /// The `finally` block to run when jumping out of the statement
+    finally: Option<Vec<Stmt>>,
+}
+
+/// Compiler state for the function currently being compiled.
+#[derive(Debug)]
+struct FunctionState {
+    proto: FunctionProto,
+    kind: FunctionKind,
+    /// Mirrors the function's stack slots; slot 0 holds the callee or `this`
+    locals: Vec<Local>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+    tries: Vec<TryBlock>,
+}
+
+impl FunctionState {
+    fn new(name: Option<String>, kind: FunctionKind) -> FunctionState {
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            _ => "",
+        };
+        FunctionState {
+            proto: FunctionProto {
+                name,
+                ..FunctionProto::default()
+            },
+            kind,
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: 0,
+                is_captured: false,
+            }],
+            scope_depth: 0,
+            loops: vec![],
+            tries: vec![],
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|local| local.name == name)
+    }
+
+    fn add_upvalue(&mut self, is_local: bool, index: usize) -> usize {
+        let upvalue = UpvalueDesc { is_local, index };
+        let upvalues = &mut self.proto.upvalues;
+        match upvalues.iter().position(|u| *u == upvalue) {
+            Some(i) => i,
+            None => {
+                upvalues.push(upvalue);
+                upvalues.len() - 1
+            }
+        }
+    }
+}
+
+/// Compiles resolved statements into bytecode for the VM.
+///
+/// Static errors have already been reported by the parser and the resolver,
+/// so compilation itself can't fail.
+pub struct Compiler {
+    functions: Vec<FunctionState>,
+    /// The token instructions are currently attributed to
+    tok: Rc<Token>,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler {
+            functions: vec![],
+            tok: Rc::new(Token::new(TokenType::Eof, "", 1, 1)),
+        }
+    }
+
+    /// Compiles top-level code. The resulting function returns the value of
+    /// the last statement if it is an expression, like the tree-walker.
+    pub fn compile(mut self, stmts: &[Stmt]) -> FunctionProto {
+        self.functions
+            .push(FunctionState::new(None, FunctionKind::Script));
+        match stmts.split_last() {
+            Some((Stmt::Expr(last), rest)) => {
+                self.stmts(rest);
+                self.expr(last);
+            }
+            _ => {
+                self.stmts(stmts);
+                self.emit(Op::Nil);
+            }
+        }
+        self.emit(Op::Return);
+        self.functions.pop().unwrap().proto
+    }
+
+    fn state(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().proto.chunk
+    }
+
+    /// Attributes the instructions that follow to `tok`.
+    fn at(&mut self, tok: &Token) {
+        if *self.tok != *tok {
+            self.tok = Rc::new(tok.clone());
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let tok = self.tok.clone();
+        self.chunk().write(op, tok)
+    }
+
+    /// Points the jump at `index` to the next instruction.
+    fn patch_jump(&mut self, index: usize) {
+        let target = self.chunk().code.len();
+        let chunk = self.chunk();
+        chunk.code[index] = match chunk.code[index] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            Op::JumpIfTrue(_) => Op::JumpIfTrue(target),
+            Op::JumpIfNil(_) => Op::JumpIfNil(target),
+            Op::JumpIfNotNil(_) => Op::JumpIfNotNil(target),
+            Op::JumpIfGiven(param, _) => Op::JumpIfGiven(param, target),
+            Op::Try(_) => Op::Try(target),
+            Op::TryFinally(_) => Op::TryFinally(target),
+            op => panic!("Can't patch {:?}", op),
+        };
+    }
+
+    fn name(&mut self, name: &str) -> usize {
+        self.chunk().add_name(name)
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+        let depth = state.scope_depth;
+        while let Some(local) = self.state().locals.pop() {
+            if local.depth <= depth {
+                self.state().locals.push(local);
+                break;
+            }
+            self.emit(if local.is_captured {
+                Op::CloseUpvalue
+            } else {
+                Op::Pop
+            });
+        }
+    }
+
+    /// Emits the instructions discarding locals deeper than `depth`, for
+    /// jumps out of their scopes. The locals stay declared.
+    fn discard_locals(&mut self, depth: usize) {
+        let ops: Vec<Op> = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| {
+                if local.is_captured {
+                    Op::CloseUpvalue
+                } else {
+                    Op::Pop
+                }
+            })
+            .collect();
+        for op in ops {
+            self.emit(op);
+        }
+    }
+
+    /// Declares a local for the value on top of the stack.
+    fn add_local(&mut self, name: &str) -> usize {
+        let state = self.state();
+        state.locals.push(Local {
+            name: name.to_owned(),
+            depth: state.scope_depth,
+            is_captured: false,
+        });
+        state.locals.len() - 1
+    }
+
+    /// Finds `name` among the upvalues of the function at `level`, capturing
+    /// it from the enclosing functions if needed.
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<usize> {
+        if level == 0 {
+            return None;
+        }
+        if let Some(local) = self.functions[level - 1].resolve_local(name) {
+            self.functions[level - 1].locals[local].is_captured = true;
+            return Some(self.functions[level].add_upvalue(true, local));
+        }
+        let upvalue = self.resolve_upvalue(level - 1, name)?;
+        Some(self.functions[level].add_upvalue(false, upvalue))
+    }
+
+    fn get_variable(&mut self, name: &Token) {
+        self.at(name);
+        let level = self.functions.len() - 1;
+        let op = if let Some(slot) = self.state().resolve_local(&name.lexeme) {
+            Op::GetLocal(slot)
+        } else if let Some(index) = self.resolve_upvalue(level, &name.lexeme) {
+            Op::GetUpvalue(index)
+        } else {
+            Op::GetGlobal(self.name(&name.lexeme))
+        };
+        self.emit(op);
+    }
+
+    fn set_variable(&mut self, name: &Token) {
+        self.at(name);
+        let level = self.functions.len() - 1;
+        let op = if let Some(slot) = self.state().resolve_local(&name.lexeme) {
+            Op::SetLocal(slot)
+        } else if let Some(index) = self.resolve_upvalue(level, &name.lexeme) {
+            Op::SetUpvalue(index)
+        } else {
+            Op::SetGlobal(self.name(&name.lexeme))
+        };
+        self.emit(op);
+    }
+
+    /// Binds the value on top of the stack to `name` in the current scope.
+    fn define_variable(&mut self, name: &Token) {
+        self.define(name, false);
+    }
+
+    /// Defines `name` with the value on top of the stack. Local constants
+    /// need no checks at runtime, as the resolver rejects assigning to them.
+    fn define(&mut self, name: &Token, constant: bool) {
+        if self.state().scope_depth > 0 {
+            self.add_local(&name.lexeme);
+        } else {
+            self.at(name);
+            let index = self.name(&name.lexeme);
+            self.emit(if constant {
+                Op::DefineConstant(index)
+            } else {
+                Op::DefineGlobal(index)
+            });
+        }
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match *stmt {
+            Stmt::Expr(ref expr) => {
+                self.expr(expr);
+                self.emit(Op::Pop);
+            }
+            Stmt::Print(ref expr) => {
+                self.expr(expr);
+                self.emit(Op::Print);
+            }
+            Stmt::Decl(ref id, ref expr, constant) => {
+                self.expr(expr);
+                self.define(&id.name, constant);
+            }
+            Stmt::Block(ref stmts) => self.block(stmts),
+            Stmt::Return(ref keyword, ref expr) => {
+                self.at(keyword);
+                if self.state().kind == FunctionKind::Initializer {
+                    self.emit(Op::GetLocal(0));
+                } else if let Some(ref expr) = *expr {
+                    self.expr(expr);
+                } else {
+                    self.emit(Op::Nil);
+                }
+                if self.state().tries.iter().any(|t| t.finally.is_some()) {
+                    // The value gets a slot of its own while `finally`
+                    // blocks run
+                    self.add_local("");
+                    self.exit_tries(0);
+                    self.state().locals.pop();
+                    self.at(keyword);
+                }
+                self.emit(Op::Return);
+            }
+            Stmt::If(ref cond, ref if_stmt, ref else_stmt) => {
+                self.expr(cond);
+                let else_jump = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                self.stmt(if_stmt);
+                let end_jump = self.emit(Op::Jump(0));
+                self.patch_jump(else_jump);
+                self.emit(Op::Pop);
+                if let Some(ref else_stmt) = *else_stmt {
+                    self.stmt(else_stmt);
+                }
+                self.patch_jump(end_jump);
+            }
+            Stmt::While(ref cond, ref body, ref increment) => {
+                let start = self.chunk().code.len();
+                self.expr(cond);
+                let exit_jump = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+
+                let scope_depth = self.state().scope_depth;
+                let tries = self.state().tries.len();
+                self.state().loops.push(Loop {
+                    scope_depth,
+                    tries,
+                    breaks: vec![],
+                    continues: vec![],
+                });
+                self.stmt(body);
+                let lp = self.state().loops.pop().unwrap();
+
+                for jump in lp.continues {
+                    self.patch_jump(jump);
+                }
+                if let Some(ref increment) = *increment {
+                    self.expr(increment);
+                    self.emit(Op::Pop);
+                }
+                self.emit(Op::Jump(start));
+                self.patch_jump(exit_jump);
+                self.emit(Op::Pop);
+                // `break` jumps past the pop of the condition, which it has
+                // already popped
+                for jump in lp.breaks {
+                    self.patch_jump(jump);
+                }
+            }
+            Stmt::Yield(ref keyword, ref value) => {
+                if let Some(ref value) = *value {
+                    self.expr(value);
+                } else {
+                    self.emit(Op::Nil);
+                }
+                self.at(keyword);
+                self.emit(Op::Yield);
+            }
+            Stmt::ForIn(ref f) => self.for_in(f),
+            Stmt::Break(ref keyword) | Stmt::Continue(ref keyword) => {
+                self.at(keyword);
+                let lp = self.state().loops.last().unwrap();
+                let (depth, tries) = (lp.scope_depth, lp.tries);
+                self.exit_tries(tries);
+                self.at(keyword);
+                self.discard_locals(depth);
+                let jump = self.emit(Op::Jump(0));
+                let lp = self.state().loops.last_mut().unwrap();
+                match *stmt {
+                    Stmt::Break(_) => lp.breaks.push(jump),
+                    _ => lp.continues.push(jump),
+                }
+            }
+            Stmt::Func(ref decl) => {
+                // Declare local functions first so they can call themselves
+                if self.state().scope_depth > 0 {
+                    self.add_local(&decl.name.lexeme);
+                    self.function(decl, FunctionKind::Function);
+                } else {
+                    self.function(decl, FunctionKind::Function);
+                    self.define_variable(&decl.name);
+                }
+            }
+            Stmt::Class(ref decl) => self.class(decl),
+            Stmt::Throw(ref keyword, ref value) => {
+                self.expr(value);
+                self.at(keyword);
+                self.emit(Op::Throw);
+            }
+            Stmt::Try(ref t) => self.try_stmt(t),
+            Stmt::Import(ref import) => self.import(import),
+        }
+    }
+
+    /// Compiles a `for-in` loop, keeping the iterator in a hidden local. The
+    /// loop variable is declared in a scope of its own, closed at the end of
+    /// every iteration.
+    fn for_in(&mut self, f: &ForInStmt) {
+        self.begin_scope();
+        self.expr(&f.iterable);
+        self.at(&f.keyword);
+        self.emit(Op::Iter);
+        self.emit(Op::Call(0));
+        let iterator = self.add_local("");
+        let done = self.name("done");
+        let next = self.name("next");
+
+        let start = self.chunk().code.len();
+        self.emit(Op::GetLocal(iterator));
+        self.emit(Op::GetProperty(done));
+        let exit_jump = self.emit(Op::JumpIfTrue(0));
+        self.emit(Op::Pop);
+        self.emit(Op::GetLocal(iterator));
+        self.emit(Op::GetProperty(next));
+        self.emit(Op::Call(0));
+
+        let scope_depth = self.state().scope_depth;
+        let tries = self.state().tries.len();
+        self.state().loops.push(Loop {
+            scope_depth,
+            tries,
+            breaks: vec![],
+            continues: vec![],
+        });
+        self.begin_scope();
+        self.add_local(&f.name.name.lexeme);
+        self.stmt(&f.body);
+        self.end_scope();
+        let lp = self.state().loops.pop().unwrap();
+
+        for jump in lp.continues {
+            self.patch_jump(jump);
+        }
+        self.emit(Op::Jump(start));
+        self.patch_jump(exit_jump);
+        self.emit(Op::Pop);
+        for jump in lp.breaks {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    /// Imports the module once for each variable it binds, as the cache makes
+    /// that cheap and keeps the locals' slots contiguous.
+    fn import(&mut self, import: &ImportStmt) {
+        let path = self.name(import.module_path());
+        for name in &import.names {
+            self.at(&import.path);
+            self.emit(Op::Import(path));
+            self.at(&name.name);
+            let index = self.name(&name.name.lexeme);
+            self.emit(Op::GetProperty(index));
+            self.define_variable(&name.name);
+        }
+        if let Some(ref alias) = import.alias {
+            self.at(&import.path);
+            self.emit(Op::Import(path));
+            self.define_variable(&alias.name);
+        } else if import.names.is_empty() {
+            self.at(&import.path);
+            self.emit(Op::Import(path));
+            self.emit(Op::Pop);
+        }
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        self.begin_scope();
+        self.stmts(stmts);
+        self.end_scope();
+    }
+
+    fn try_stmt(&mut self, t: &TryStmt) {
+        let locals = self.state().locals.len();
+        let finally = match t.finally {
+            Some(ref finally) => finally,
+            None => return self.try_catch(t, locals),
+        };
+        self.at(&t.keyword);
+        let handler = self.emit(Op::TryFinally(0));
+        self.state().tries.push(TryBlock {
+            locals,
+            finally: Some(finally.clone()),
+        });
+        self.try_catch(t, locals);
+        self.state().tries.pop();
+        self.emit(Op::PopTry);
+        self.block(finally);
+        let end = self.emit(Op::Jump(0));
+
+        // On an error, run the block with the error's reference in a hidden
+        // local and then raise it again
+        self.patch_jump(handler);
+        self.begin_scope();
+        let slot = self.add_local("");
+        self.stmts(finally);
+        self.at(&t.keyword);
+        self.emit(Op::GetLocal(slot));
+        self.emit(Op::Rethrow);
+        self.end_scope();
+        self.patch_jump(end);
+    }
+
+    /// Compiles the body of a `try` statement and its `catch` clause.
+    fn try_catch(&mut self, t: &TryStmt, locals: usize) {
+        let (name, handler_body) = match t.catch {
+            Some((ref name, ref handler_body)) => (name, handler_body),
+            None => return self.block(&t.body),
+        };
+        self.at(&t.keyword);
+        let handler = self.emit(Op::Try(0));
+        self.state().tries.push(TryBlock {
+            locals,
+            finally: None,
+        });
+        self.block(&t.body);
+        self.state().tries.pop();
+        self.emit(Op::PopTry);
+        let end = self.emit(Op::Jump(0));
+
+        // The caught value is on top of the stack
+        self.patch_jump(handler);
+        self.begin_scope();
+        self.add_local(&name.name.lexeme);
+        self.stmts(handler_body);
+        self.end_scope();
+        self.patch_jump(end);
+    }
+
+    /// Uninstalls the handlers of the `try` statements a jump leaves, from
+    /// the innermost out to the one at `depth`, running their `finally`
+    /// blocks.
+    fn exit_tries(&mut self, depth: usize) {
+        for index in (depth..self.state().tries.len()).rev() {
+            self.emit(Op::PopTry);
+            self.inline_finally(index);
+        }
+    }
+
+    /// Compiles the `finally` block of the `try` statement at `index` for a
+    /// jump out of it. Locals declared inside the statement are hidden so
+    /// the block sees the same variables as it does lexically.
+    fn inline_finally(&mut self, index: usize) {
+        let state = self.state();
+        let finally = match state.tries[index].finally {
+            Some(ref finally) => finally.clone(),
+            None => return,
+        };
+        let start = state.tries[index].locals;
+        let inner = state.tries.split_off(index);
+        let names: Vec<String> = state.locals[start..]
+            .iter_mut()
+            .map(|local| mem::take(&mut local.name))
+            .collect();
+
+        self.block(&finally);
+
+        let state = self.state();
+        for (local, name) in state.locals[start..].iter_mut().zip(names) {
+            local.name = name;
+        }
+        state.tries.extend(inner);
+    }
+
+    fn class(&mut self, decl: &ClassDecl) {
+        // A local class gets its slot up front so methods can refer to it
+        let slot = if self.state().scope_depth > 0 {
+            self.at(&decl.name);
+            self.emit(Op::Nil);
+            Some(self.add_local(&decl.name.lexeme))
+        } else {
+            None
+        };
+
+        if let Some(ref superclass) = decl.superclass {
+            self.get_variable(&superclass.name);
+            self.emit(Op::Superclass);
+            // Methods capture the superclass as a local named `super`
+            self.begin_scope();
+            self.add_local("super");
+        }
+
+        for method in &decl.methods {
+            let kind = if method.name.lexeme == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.function(method, kind);
+        }
+
+        self.at(&decl.name);
+        let name = self.name(&decl.name.lexeme);
+        self.emit(Op::Class(
+            name,
+            decl.methods.len(),
+            decl.superclass.is_some(),
+        ));
+        match slot {
+            Some(slot) => {
+                self.emit(Op::SetLocal(slot));
+                self.emit(Op::Pop);
+            }
+            None => {
+                self.emit(Op::DefineGlobal(name));
+            }
+        }
+
+        if decl.superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    /// Compiles `decl` as a new function and emits the closure creating it.
+    fn function(&mut self, decl: &FunctionDecl, kind: FunctionKind) {
+        self.at(&decl.name);
+        self.functions
+            .push(FunctionState::new(Some(decl.name.lexeme.clone()), kind));
+        self.state().proto.arity = decl.arity();
+        self.state().proto.is_generator = decl.is_generator;
+        self.begin_scope();
+        // The call leaves a slot for each parameter, so a default only has
+        // to fill its slot in. Compiling it before declaring its parameter
+        // keeps the later parameters out of its scope.
+        for (i, param) in decl.params.iter().enumerate() {
+            if let Some(ref default) = param.default {
+                let given = self.emit(Op::JumpIfGiven(i, 0));
+                self.expr(default);
+                self.emit(Op::SetLocal(i + 1));
+                self.emit(Op::Pop);
+                self.patch_jump(given);
+            }
+            self.add_local(&param.name.lexeme);
+            self.state().proto.params.push(param.name.lexeme.clone());
+        }
+        if let Some(ref rest) = decl.rest {
+            self.add_local(&rest.lexeme);
+        }
+        self.stmts(&decl.body);
+
+        self.at(&decl.name);
+        if kind == FunctionKind::Initializer {
+            self.emit(Op::GetLocal(0));
+        } else {
+            self.emit(Op::Nil);
+        }
+        self.emit(Op::Return);
+
+        let proto = self.functions.pop().unwrap().proto;
+        let index = self.chunk().add_function(proto);
+        self.emit(Op::Closure(index));
+    }
+
+    /// Compiles the arguments of a call and the call. Named arguments go
+    /// after the others, with their names kept in the chunk. If there are
+    /// `...list` arguments, the others are passed as lists too, with
+    /// consecutive positional arguments gathered into one.
+    fn call(&mut self, paren: &Token, args: &[Arg]) {
+        let spread = args.iter().any(|arg| matches!(arg, Arg::Spread(..)));
+        let mut positional = 0;
+        let mut lists = 0;
+        let mut names = vec![];
+        for arg in args {
+            if spread && positional > 0 && !matches!(arg, Arg::Positional(_)) {
+                self.emit(Op::List(positional));
+                lists += 1;
+                positional = 0;
+            }
+            match *arg {
+                Arg::Positional(ref value) => {
+                    self.expr(value);
+                    positional += 1;
+                }
+                Arg::Spread(ref tok, ref list) => {
+                    self.expr(list);
+                    self.at(tok);
+                    self.emit(Op::Spread);
+                    lists += 1;
+                }
+                Arg::Named(ref name, ref value) => {
+                    self.expr(value);
+                    names.push(name.lexeme.clone());
+                }
+            }
+        }
+        if spread && positional > 0 {
+            self.emit(Op::List(positional));
+            lists += 1;
+        }
+        let names = (!names.is_empty()).then(|| self.chunk().add_arg_names(names));
+        self.at(paren);
+        self.emit(match (spread, names) {
+            (true, names) => Op::CallSpread(lists, names),
+            (false, Some(names)) => Op::CallNamed(positional, names),
+            (false, None) => Op::Call(positional),
+        });
+    }
+
+    /// Compiles a compound assignment or increment. The target's parts are
+    /// duplicated on the stack so they are only evaluated once, and for
+    /// postfix updates a copy of the old value is buried beneath them.
+    fn update(&mut self, u: &UpdateExpr) {
+        match u.target {
+            Expr::Variable(ref id) => {
+                self.get_variable(&id.name);
+                if u.postfix {
+                    self.emit(Op::Dup(0));
+                }
+                self.apply_update(u);
+                self.set_variable(&id.name);
+            }
+            Expr::Get(ref object, ref name) => {
+                let index = self.name(&name.lexeme);
+                self.expr(object);
+                self.emit(Op::Dup(0));
+                self.at(name);
+                self.emit(Op::GetProperty(index));
+                if u.postfix {
+                    self.emit(Op::Dup(0));
+                    self.emit(Op::Bury(2));
+                }
+                self.apply_update(u);
+                self.at(name);
+                self.emit(Op::SetProperty(index));
+            }
+            Expr::Index(ref target, ref bracket, ref index) => {
+                self.expr(target);
+                self.expr(index);
+                self.emit(Op::Dup(1));
+                self.emit(Op::Dup(1));
+                self.at(bracket);
+                self.emit(Op::GetIndex);
+                if u.postfix {
+                    self.emit(Op::Dup(0));
+                    self.emit(Op::Bury(3));
+                }
+                self.apply_update(u);
+                self.at(bracket);
+                self.emit(Op::SetIndex);
+            }
+            _ => unreachable!("the parser only builds updates of assignable targets"),
+        }
+        if u.postfix {
+            self.emit(Op::Pop);
+        }
+    }
+
+    fn apply_update(&mut self, u: &UpdateExpr) {
+        self.expr(&u.value);
+        self.at(&u.tok);
+        self.emit(Op::Binary(u.op));
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match *expr {
+            Expr::Literal(ref value) => {
+                let op = match *value {
+                    Value::Nil => Op::Nil,
+                    Value::Bool(true) => Op::True,
+                    Value::Bool(false) => Op::False,
+                    ref value => Op::Constant(self.chunk().add_constant(value.clone())),
+                };
+                self.emit(op);
+            }
+            Expr::Unary(ref u) => {
+                self.expr(&u.expr);
+                self.at(&u.tok);
+                self.emit(Op::Unary(u.op));
+            }
+            Expr::Binary(ref b) => {
+                self.expr(&b.left);
+                self.expr(&b.right);
+                self.at(&b.tok);
+                self.emit(Op::Binary(b.op));
+            }
+            Expr::Logical(ref l) => {
+                self.expr(&l.left);
+                let jump = self.emit(match l.op {
+                    LogicalOperator::And => Op::JumpIfFalse(0),
+                    LogicalOperator::Or => Op::JumpIfTrue(0),
+                    LogicalOperator::Coalesce => Op::JumpIfNotNil(0),
+                });
+                self.emit(Op::Pop);
+                self.expr(&l.right);
+                self.patch_jump(jump);
+            }
+            Expr::Conditional(ref cond, ref then, ref otherwise) => {
+                self.expr(cond);
+                let else_jump = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::Pop);
+                self.expr(then);
+                let end_jump = self.emit(Op::Jump(0));
+                self.patch_jump(else_jump);
+                self.emit(Op::Pop);
+                self.expr(otherwise);
+                self.patch_jump(end_jump);
+            }
+            Expr::Chain(ref object, ref rest) => {
+                self.expr(object);
+                let jump = self.emit(Op::JumpIfNil(0));
+                self.expr(rest);
+                self.patch_jump(jump);
+            }
+            // The chain's object is already on top of the stack
+            Expr::Chained => {}
+            Expr::Grouping(ref g) => self.expr(&g.expr),
+            Expr::Variable(ref id) => self.get_variable(&id.name),
+            Expr::Assign(ref id, ref value) => {
+                self.expr(value);
+                self.set_variable(&id.name);
+            }
+            Expr::Update(ref u) => self.update(u),
+            Expr::Function(ref decl) => self.function(decl, FunctionKind::Function),
+            Expr::Get(ref object, ref name) => {
+                self.expr(object);
+                self.at(name);
+                let index = self.name(&name.lexeme);
+                self.emit(Op::GetProperty(index));
+            }
+            Expr::Set(ref object, ref name, ref value) => {
+                self.expr(object);
+                self.expr(value);
+                self.at(name);
+                let index = self.name(&name.lexeme);
+                self.emit(Op::SetProperty(index));
+            }
+            Expr::List(ref bracket, ref items) => {
+                for item in items {
+                    self.expr(item);
+                }
+                self.at(bracket);
+                self.emit(Op::List(items.len()));
+            }
+            Expr::Interpolate(ref parts) => {
+                for part in parts {
+                    self.expr(part);
+                }
+                self.emit(Op::Interpolate(parts.len()));
+            }
+            Expr::Map(ref brace, ref entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                self.at(brace);
+                self.emit(Op::Map(entries.len()));
+            }
+            Expr::Index(ref target, ref bracket, ref index) => {
+                self.expr(target);
+                self.expr(index);
+                self.at(bracket);
+                self.emit(Op::GetIndex);
+            }
+            Expr::SetIndex(ref target, ref bracket, ref index, ref value) => {
+                self.expr(target);
+                self.expr(index);
+                self.expr(value);
+                self.at(bracket);
+                self.emit(Op::SetIndex);
+            }
+            Expr::This(ref keyword) => self.get_variable(&keyword.name),
+            Expr::Super(ref keyword, ref method) => {
+                let this = Token::new(TokenType::This, "this", keyword.name.line, keyword.name.col);
+                self.get_variable(&this);
+                self.get_variable(&keyword.name);
+                self.at(method);
+                let index = self.name(&method.lexeme);
+                self.emit(Op::GetSuper(index));
+            }
+            Expr::Call(ref callee, ref paren, ref args) => {
+                self.expr(callee);
+                self.call(paren, args);
+            }
+        }
+    }
+}
@@ -0,0 +1,769 @@
+//! A bytecode compiler and stack-based virtual machine, an alternative to the
+//! tree-walking interpreter that avoids walking environments on every access.
+
+pub mod chunk;
+pub mod compiler;
+pub mod object;
+
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use crate::ast::Value;
+use crate::builtins;
+use crate::callable::{builtin_args, match_args, Named};
+use crate::class::{bind, undefined_property, Class, Instance};
+use crate::errors::{ErrorKind, Result, TraceFrame};
+use crate::evaluable::{binary, get_index, get_property, set_index, set_property, spread, unary};
+use crate::exception;
+use crate::generator::{self, Frame, Generator};
+use crate::interpreter::{Interpreter, MAX_FRAMES};
+use crate::iterator::iter_method;
+use crate::list;
+use crate::map::{self, Key, Map};
+use crate::vm::chunk::Op;
+use crate::vm::object::{Closure, FunctionProto, GlobalScope, Globals, Upvalue, UpvalueRef};
+
+/// An active call of a closure.
+struct CallFrame {
+    closure: Rc<Closure>,
+    /// Index of the next instruction in the closure's chunk
+    ip: usize,
+    /// Stack index of the frame's slot 0
+    base: usize,
+    /// The `try` statements this call is inside of, innermost last
+    handlers: Vec<Handler>,
+    /// The generator this call is the body of
+    generator: Option<Rc<Generator>>,
+    /// Which parameters got no arguments, for `JumpIfGiven`. Empty if none.
+    missing: Vec<bool>,
+}
+
+/// The frame of a suspended generator, with stack positions relative to its
+/// base.
+pub struct GeneratorFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// The frame's stack slots, the closure itself first
+    slots: Vec<Value>,
+    handlers: Vec<Handler>,
+    /// The upvalues capturing the frame's slots, closed while it's suspended
+    upvalues: Vec<(usize, UpvalueRef)>,
+    /// The parameters whose defaults are evaluated when the body starts
+    missing: Vec<bool>,
+}
+
+/// Where to continue if an exception is raised inside a `try` statement.
+struct Handler {
+    /// Instruction to jump to
+    target: usize,
+    /// Height to cut the stack back to before pushing the exception
+    stack_len: usize,
+    /// Whether the handler runs a `finally` block rather than a `catch`
+    finally: bool,
+}
+
+/// Executes compiled code. Globals persist between runs, like the
+/// tree-walker's global environment.
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: Globals,
+    /// Upvalues still pointing into the stack, ordered by slot
+    open_upvalues: Vec<UpvalueRef>,
+    /// Errors set aside while `finally` blocks run, with the value being
+    /// thrown if there is one
+    pending: Vec<(ErrorKind, Option<Value>)>,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            stack: vec![],
+            frames: vec![],
+            globals: Rc::new(RefCell::new(GlobalScope::new(
+                builtins::globals()
+                    .into_iter()
+                    .map(|(name, value)| (name.to_owned(), value))
+                    .collect(),
+            ))),
+            open_upvalues: vec![],
+            pending: vec![],
+        }
+    }
+
+    /// The variables defined by the scripts this VM has run.
+    pub fn globals(&self) -> Globals {
+        self.globals.clone()
+    }
+
+    /// Runs a compiled script, sending output through `interpreter`.
+    pub fn run(&mut self, interpreter: &mut Interpreter, script: FunctionProto) -> Result<Value> {
+        let closure = Rc::new(Closure {
+            proto: Rc::new(script),
+            upvalues: vec![],
+            globals: self.globals.clone(),
+        });
+        self.stack.push(Value::Closure(closure.clone()));
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: 0,
+            handlers: vec![],
+            generator: None,
+            missing: vec![],
+        });
+        let res = self.run_frames(interpreter, 1);
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.pending.clear();
+        res
+    }
+
+    /// Runs until the frame `floor - 1` returns or yields, handling errors
+    /// with the handlers of that frame and the ones above it.
+    fn run_frames(&mut self, interpreter: &mut Interpreter, floor: usize) -> Result<Value> {
+        loop {
+            match self.execute(interpreter, floor) {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    let e = self.locate(e);
+                    self.unwind(interpreter, e, floor)?;
+                }
+            }
+        }
+    }
+
+    /// Runs a generator up to its next `yield`, unless it already knows its
+    /// next value. Its frame goes on top of the current ones, so a generator
+    /// that raises an error leaves them as they were.
+    fn resume(&mut self, interpreter: &mut Interpreter, generator: &Rc<Generator>) -> Result<()> {
+        let frame = match generator.resume()? {
+            Some(Frame::Vm(frame)) => frame,
+            Some(Frame::Tree(_)) => unreachable!("VM resumed a tree-walker generator"),
+            None => return Ok(()),
+        };
+        if self.frames.len() > MAX_FRAMES {
+            generator.finish();
+            return Err(ErrorKind::EvaluateError("Stack overflow.".to_string()));
+        }
+        let base = self.stack.len();
+        self.stack.extend(frame.slots);
+        for (slot, upvalue) in frame.upvalues {
+            let closed = mem::replace(&mut *upvalue.borrow_mut(), Upvalue::Open(base + slot));
+            if let Upvalue::Closed(value) = closed {
+                self.stack[base + slot] = value;
+            }
+            self.open_upvalues.push(upvalue);
+        }
+        let handlers = frame
+            .handlers
+            .into_iter()
+            .map(|handler| Handler {
+                stack_len: base + handler.stack_len,
+                ..handler
+            })
+            .collect();
+        self.frames.push(CallFrame {
+            closure: frame.closure,
+            ip: frame.ip,
+            base,
+            handlers,
+            generator: Some(generator.clone()),
+            missing: frame.missing,
+        });
+
+        let floor = self.frames.len();
+        let res = self.run_frames(interpreter, floor);
+        if res.is_err() {
+            self.frames.truncate(floor - 1);
+            self.close_upvalues(base);
+            self.stack.truncate(base);
+            generator.finish();
+        }
+        res.map(|_| ())
+    }
+
+    /// Takes the top frame off the stack for its generator to resume later.
+    fn suspend(&mut self, value: Value) {
+        let frame = self.frames.pop().unwrap();
+        let base = frame.base;
+        let captured = self
+            .open_upvalues
+            .iter()
+            .position(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s >= base))
+            .unwrap_or(self.open_upvalues.len());
+        let upvalues = self
+            .open_upvalues
+            .split_off(captured)
+            .into_iter()
+            .map(|upvalue| {
+                let slot = match *upvalue.borrow() {
+                    Upvalue::Open(slot) => slot,
+                    Upvalue::Closed(_) => unreachable!("closed upvalue in the open list"),
+                };
+                *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot].clone());
+                (slot - base, upvalue)
+            })
+            .collect();
+        let handlers = frame
+            .handlers
+            .into_iter()
+            .map(|handler| Handler {
+                stack_len: handler.stack_len - base,
+                ..handler
+            })
+            .collect();
+        let generator = frame.generator.expect("yield outside of a generator");
+        let saved = GeneratorFrame {
+            closure: frame.closure,
+            ip: frame.ip,
+            slots: self.stack.split_off(base),
+            handlers,
+            upvalues,
+            // The defaults were evaluated before anything could yield
+            missing: vec![],
+        };
+        generator.suspend(value, Frame::Vm(saved));
+    }
+
+    /// Transfers control to the innermost handler at or above the frame
+    /// `floor - 1` that takes `e`, unwinding the frames above it, or hands
+    /// `e` back if there is none.
+    fn unwind(
+        &mut self,
+        interpreter: &mut Interpreter,
+        mut e: ErrorKind,
+        floor: usize,
+    ) -> Result<()> {
+        while let Some(depth) = self.frames[floor - 1..]
+            .iter()
+            .rposition(|f| !f.handlers.is_empty())
+            .map(|depth| depth + floor - 1)
+        {
+            let handler = self.frames[depth].handlers.pop().unwrap();
+            let value = if handler.finally {
+                self.pending.push((e, interpreter.thrown.take()));
+                Value::Number((self.pending.len() - 1) as f64)
+            } else {
+                match exception::catch(interpreter, e) {
+                    Ok(value) => value,
+                    Err(uncaught) => {
+                        e = uncaught;
+                        continue;
+                    }
+                }
+            };
+            self.frames.truncate(depth + 1);
+            self.close_upvalues(handler.stack_len);
+            self.stack.truncate(handler.stack_len);
+            self.stack.push(value);
+            self.frames[depth].ip = handler.target;
+            return Ok(());
+        }
+        Err(e)
+    }
+
+    /// Attributes an error to the instruction being executed and records the
+    /// active frames as its stack trace.
+    fn locate(&self, e: ErrorKind) -> ErrorKind {
+        let frame = self.frames.last().unwrap();
+        let tok = frame.closure.proto.chunk.tokens[frame.ip - 1].clone();
+        match e.at(&tok) {
+            ErrorKind::RuntimeError { tok, t, trace } if trace.is_empty() => {
+                let trace = self
+                    .frames
+                    .iter()
+                    .rev()
+                    .map(|frame| TraceFrame {
+                        line: frame.closure.proto.chunk.tokens[frame.ip - 1].line,
+                        function: frame.closure.proto.name.clone(),
+                    })
+                    .collect();
+                ErrorKind::RuntimeError { tok, t, trace }
+            }
+            e => e,
+        }
+    }
+
+    /// Executes instructions until the frame `floor - 1` returns or yields.
+    fn execute(&mut self, interpreter: &mut Interpreter, floor: usize) -> Result<Value> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.closure.proto.chunk.code[frame.ip];
+            frame.ip += 1;
+            let base = frame.base;
+
+            match op {
+                Op::Constant(index) => {
+                    let value = self.closure().proto.chunk.constants[index].clone();
+                    self.stack.push(value);
+                }
+                Op::Nil => self.stack.push(Value::Nil),
+                Op::True => self.stack.push(Value::Bool(true)),
+                Op::False => self.stack.push(Value::Bool(false)),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Dup(distance) => {
+                    let value = self.peek(distance).clone();
+                    self.stack.push(value);
+                }
+                Op::Bury(depth) => {
+                    let value = self.pop();
+                    self.stack.insert(self.stack.len() - depth, value);
+                }
+                Op::GetLocal(slot) => {
+                    let value = self.stack[base + slot].clone();
+                    self.stack.push(value);
+                }
+                Op::SetLocal(slot) => {
+                    self.stack[base + slot] = self.peek(0).clone();
+                }
+                Op::GetUpvalue(index) => {
+                    let upvalue = self.closure().upvalues[index].clone();
+                    let value = match *upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[slot].clone(),
+                        Upvalue::Closed(ref value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::SetUpvalue(index) => {
+                    let upvalue = self.closure().upvalues[index].clone();
+                    let value = self.peek(0).clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match *upvalue {
+                        Upvalue::Open(slot) => self.stack[slot] = value,
+                        Upvalue::Closed(ref mut closed) => *closed = value,
+                    }
+                }
+                Op::GetGlobal(index) => {
+                    let closure = self.closure();
+                    let name = &closure.proto.chunk.names[index];
+                    let value = closure.globals.borrow().get(name).cloned();
+                    match value {
+                        Some(value) => self.stack.push(value),
+                        None => return Err(undefined_variable(name)),
+                    }
+                }
+                Op::DefineGlobal(index) | Op::DefineConstant(index) => {
+                    let closure = self.closure();
+                    let name = &closure.proto.chunk.names[index];
+                    let value = self.pop();
+                    let constant = matches!(op, Op::DefineConstant(_));
+                    closure.globals.borrow_mut().define(name, value, constant)?;
+                }
+                Op::SetGlobal(index) => {
+                    let closure = self.closure();
+                    let name = &closure.proto.chunk.names[index];
+                    let value = self.peek(0).clone();
+                    if !closure.globals.borrow_mut().assign(name, value)? {
+                        return Err(undefined_variable(name));
+                    }
+                }
+                Op::GetProperty(index) => {
+                    let closure = self.closure();
+                    let name = &closure.proto.chunk.names[index];
+                    let object = self.pop();
+                    if let Some(generator) = generator::runs_on_get(&object, name) {
+                        self.resume(interpreter, generator)?;
+                    }
+                    self.stack.push(get_property(object, name)?);
+                }
+                Op::SetProperty(index) => {
+                    let closure = self.closure();
+                    let name = &closure.proto.chunk.names[index];
+                    let value = self.pop();
+                    let object = self.pop();
+                    self.stack.push(set_property(object, name, value)?);
+                }
+                Op::GetSuper(index) => {
+                    let closure = self.closure();
+                    let name = &closure.proto.chunk.names[index];
+                    let superclass = self.pop();
+                    let this = self.pop();
+                    match (superclass, this) {
+                        (Value::Class(superclass), Value::Instance(instance)) => {
+                            match superclass.find_method(name) {
+                                Some(method) => self.stack.push(bind(method, instance)),
+                                None => return Err(undefined_property(name)),
+                            }
+                        }
+                        _ => {
+                            return Err(ErrorKind::EvaluateError(
+                                "Can't use 'super' outside of a method.".to_string(),
+                            ))
+                        }
+                    }
+                }
+                Op::List(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(list::new(items));
+                }
+                Op::Interpolate(count) => {
+                    let parts = self.stack.split_off(self.stack.len() - count);
+                    let s = parts.iter().map(Value::stringify).collect();
+                    self.stack.push(Value::String(s));
+                }
+                Op::Map(count) => {
+                    let items = self.stack.split_off(self.stack.len() - 2 * count);
+                    let mut map = Map::default();
+                    for pair in items.chunks(2) {
+                        map.insert(Key::new(&pair[0])?, pair[1].clone());
+                    }
+                    self.stack.push(map::new(map));
+                }
+                Op::GetIndex => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    self.stack.push(get_index(&target, &index)?);
+                }
+                Op::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let target = self.pop();
+                    self.stack.push(set_index(&target, &index, value)?);
+                }
+                Op::Unary(op) => {
+                    let value = self.pop();
+                    self.stack.push(unary(op, value)?);
+                }
+                Op::Binary(op) => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.stack.push(binary(op, left, right)?);
+                }
+                Op::Print => {
+                    let value = self.pop();
+                    interpreter.output().println(&value.stringify())?;
+                }
+                Op::Jump(target) => self.frame().ip = target,
+                Op::JumpIfFalse(target) => {
+                    if !self.peek(0).is_truthy() {
+                        self.frame().ip = target;
+                    }
+                }
+                Op::JumpIfTrue(target) => {
+                    if self.peek(0).is_truthy() {
+                        self.frame().ip = target;
+                    }
+                }
+                Op::JumpIfNil(target) => {
+                    if *self.peek(0) == Value::Nil {
+                        self.frame().ip = target;
+                    }
+                }
+                Op::JumpIfNotNil(target) => {
+                    if *self.peek(0) != Value::Nil {
+                        self.frame().ip = target;
+                    }
+                }
+                Op::JumpIfGiven(param, target) => {
+                    if !self.frame().missing.get(param).copied().unwrap_or(false) {
+                        self.frame().ip = target;
+                    }
+                }
+                Op::Call(argc) => {
+                    let callee = self.peek(argc).clone();
+                    self.call_value(interpreter, callee, argc, vec![])?;
+                }
+                Op::CallNamed(argc, names) => {
+                    let named = self.named_args(names);
+                    let callee = self.peek(argc).clone();
+                    self.call_value(interpreter, callee, argc, named)?;
+                }
+                Op::Spread => {
+                    let items = spread(&self.pop())?;
+                    self.stack.push(list::new(items));
+                }
+                Op::CallSpread(count, names) => {
+                    let named = names.map_or_else(Vec::new, |names| self.named_args(names));
+                    let lists = self.stack.split_off(self.stack.len() - count);
+                    let start = self.stack.len();
+                    for list in lists {
+                        if let Value::List(list) = list {
+                            self.stack.extend(list.borrow().iter().cloned());
+                        }
+                    }
+                    let argc = self.stack.len() - start;
+                    let callee = self.peek(argc).clone();
+                    self.call_value(interpreter, callee, argc, named)?;
+                }
+                Op::Closure(index) => {
+                    let closure = self.closure();
+                    let proto = closure.proto.chunk.functions[index].clone();
+                    let upvalues = proto
+                        .upvalues
+                        .iter()
+                        .map(|desc| {
+                            if desc.is_local {
+                                self.capture_upvalue(base + desc.index)
+                            } else {
+                                closure.upvalues[desc.index].clone()
+                            }
+                        })
+                        .collect();
+                    self.stack.push(Value::Closure(Rc::new(Closure {
+                        proto,
+                        upvalues,
+                        globals: closure.globals.clone(),
+                    })));
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    self.close_upvalues(base);
+                    let frame = self.frames.pop().unwrap();
+                    self.stack.truncate(base);
+                    if let Some(generator) = frame.generator {
+                        generator.finish();
+                    }
+                    if self.frames.len() < floor {
+                        return Ok(result);
+                    }
+                    self.stack.push(result);
+                }
+                Op::Yield => {
+                    let value = self.pop();
+                    self.suspend(value);
+                    // Generator frames are only run by `resume`, as the floor
+                    return Ok(Value::Nil);
+                }
+                Op::Try(target) | Op::TryFinally(target) => {
+                    let stack_len = self.stack.len();
+                    self.frame().handlers.push(Handler {
+                        target,
+                        stack_len,
+                        finally: matches!(op, Op::TryFinally(_)),
+                    });
+                }
+                Op::PopTry => {
+                    self.frame().handlers.pop();
+                }
+                Op::Iter => {
+                    let iterable = self.pop();
+                    self.stack.push(iter_method(iterable)?);
+                }
+                Op::Import(index) => {
+                    let closure = self.closure();
+                    let path = &closure.proto.chunk.names[index];
+                    let module = interpreter.import(path)?;
+                    self.stack.push(module);
+                }
+                Op::Throw => {
+                    let value = self.pop();
+                    return Err(exception::throw(interpreter, value));
+                }
+                Op::Rethrow => {
+                    let index = match self.pop() {
+                        Value::Number(n) => n as usize,
+                        _ => unreachable!("finally handlers keep a number on the stack"),
+                    };
+                    // Errors set aside by handlers that were exited early
+                    // by a new exception are abandoned
+                    self.pending.truncate(index + 1);
+                    let (e, thrown) = self.pending.pop().unwrap();
+                    interpreter.thrown = thrown;
+                    return Err(e);
+                }
+                Op::Superclass => {
+                    if !matches!(self.peek(0), Value::Class(_)) {
+                        return Err(ErrorKind::EvaluateError(
+                            "Superclass must be a class.".to_string(),
+                        ));
+                    }
+                }
+                Op::Class(name, method_count, inherits) => {
+                    let name = self.closure().proto.chunk.names[name].clone();
+                    let methods = self
+                        .stack
+                        .split_off(self.stack.len() - method_count)
+                        .into_iter()
+                        .map(|method| match method {
+                            Value::Closure(ref closure) => (closure.name().to_owned(), method),
+                            _ => unreachable!("methods compile to closures"),
+                        })
+                        .collect();
+                    let superclass = match (inherits, self.stack.last()) {
+                        (true, Some(Value::Class(superclass))) => Some(superclass.clone()),
+                        _ => None,
+                    };
+                    let class = Class::new(&name, superclass, methods);
+                    self.stack.push(Value::Class(Rc::new(class)));
+                }
+            }
+        }
+    }
+
+    /// Pops the values of a call's named arguments, whose names are the
+    /// given entry of the chunk's argument names.
+    fn named_args(&mut self, names: usize) -> Named {
+        let closure = self.closure();
+        let names = &closure.proto.chunk.arg_names[names];
+        let values = self.stack.split_off(self.stack.len() - names.len());
+        names.iter().cloned().zip(values).collect()
+    }
+
+    fn call_value(
+        &mut self,
+        interpreter: &mut Interpreter,
+        callee: Value,
+        argc: usize,
+        named: Named,
+    ) -> Result<()> {
+        let slot = self.stack.len() - argc - 1;
+        match callee {
+            Value::Closure(closure) => self.call_closure(closure, argc, named),
+            Value::BoundMethod(bound) => {
+                self.stack[slot] = bound.receiver.clone();
+                self.call_closure(bound.method.clone(), argc, named)
+            }
+            Value::Class(class) => {
+                let instance = Rc::new(RefCell::new(Instance::new(class.clone())));
+                self.stack[slot] = Value::Instance(instance);
+                match class.find_method("init") {
+                    Some(Value::Closure(init)) => self.call_closure(init, argc, named),
+                    _ => builtin_args(&[], self.stack.split_off(slot + 1), named).map(|_| ()),
+                }
+            }
+            Value::BuiltinFunc(_, params, func) => {
+                let args = builtin_args(params, self.stack.split_off(slot + 1), named)?;
+                self.stack.pop();
+                let result = func(interpreter, args)?;
+                self.stack.push(result);
+                Ok(())
+            }
+            Value::BuiltinMethod(ref receiver, _, params, func) => {
+                let mut args = builtin_args(params, self.stack.split_off(slot + 1), named)?;
+                if let Some(generator) = generator::runs_on_call(&callee) {
+                    self.resume(interpreter, generator)?;
+                }
+                // The receiver takes the callee's place as the first argument
+                self.stack.pop();
+                args.insert(0, (**receiver).clone());
+                let result = func(interpreter, args)?;
+                self.stack.push(result);
+                Ok(())
+            }
+            callee => Err(ErrorKind::EvaluateError(format!(
+                "{} is not a valid function",
+                callee
+            ))),
+        }
+    }
+
+    fn call_closure(&mut self, closure: Rc<Closure>, argc: usize, named: Named) -> Result<()> {
+        let arity = closure.proto.arity;
+        let base = self.stack.len() - argc - 1;
+        // Parameters without arguments get a slot for their default
+        let (missing, rest) = if named.is_empty() {
+            arity.check(argc)?;
+            let given = argc.min(arity.params());
+            let rest = self.stack.split_off(base + 1 + given);
+            self.stack.resize(base + 1 + arity.params(), Value::Nil);
+            let mut missing = vec![];
+            if given < arity.params() {
+                missing.resize(given, false);
+                missing.resize(arity.params(), true);
+            }
+            (missing, rest)
+        } else {
+            let args = self.stack.split_off(base + 1);
+            let (values, rest) = match_args(&closure.proto.params, arity, args, named)?;
+            let missing = values.iter().map(Option::is_none).collect();
+            self.stack
+                .extend(values.into_iter().map(|value| value.unwrap_or(Value::Nil)));
+            (missing, rest)
+        };
+        if arity.variadic {
+            self.stack.push(list::new(rest));
+        }
+
+        if closure.proto.is_generator {
+            let slots = self.stack.split_off(base);
+            let name = closure.name().to_owned();
+            let frame = GeneratorFrame {
+                closure,
+                ip: 0,
+                slots,
+                handlers: vec![],
+                upvalues: vec![],
+                missing,
+            };
+            let generator = Generator::new(&name, Frame::Vm(frame));
+            self.stack.push(Value::Generator(Rc::new(generator)));
+            return Ok(());
+        }
+        // The script's own frame doesn't count towards the limit
+        if self.frames.len() > MAX_FRAMES {
+            return Err(ErrorKind::EvaluateError("Stack overflow.".to_string()));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base,
+            handlers: vec![],
+            generator: None,
+            missing,
+        });
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> UpvalueRef {
+        let position = self
+            .open_upvalues
+            .iter()
+            .rposition(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s <= slot));
+        if let Some(i) = position {
+            if matches!(*self.open_upvalues[i].borrow(), Upvalue::Open(s) if s == slot) {
+                return self.open_upvalues[i].clone();
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        let index = position.map_or(0, |i| i + 1);
+        self.open_upvalues.insert(index, upvalue.clone());
+        upvalue
+    }
+
+    /// Moves every captured variable at or above `slot` off the stack.
+    fn close_upvalues(&mut self, slot: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let open_slot = match *upvalue.borrow() {
+                Upvalue::Open(s) if s >= slot => s,
+                _ => break,
+            };
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[open_slot].clone());
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn closure(&self) -> Rc<Closure> {
+        self.frames.last().unwrap().closure.clone()
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("VM stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+}
+
+fn undefined_variable(name: &str) -> ErrorKind {
+    ErrorKind::EvaluateError(format!("Undefined variable: {}", name))
+}
@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::Value;
+use crate::callable::Arity;
+use crate::errors::{ErrorKind, Result};
+use crate::vm::chunk::Chunk;
+
+/// Where a closure finds one of its captured variables when it is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalueDesc {
+    /// Whether the variable is a local of the enclosing function, as opposed
+    /// to one of its upvalues
+    pub is_local: bool,
+    pub index: usize,
+}
+
+/// A compiled function, shared by every closure created from it.
+#[derive(Debug, Default)]
+pub struct FunctionProto {
+    /// `None` for top-level code
+    pub name: Option<String>,
+    pub arity: Arity,
+    /// The names of the parameters before the rest parameter
+    pub params: Vec<String>,
+    /// Whether calls return a generator instead of running the function
+    pub is_generator: bool,
+    pub chunk: Chunk,
+    pub upvalues: Vec<UpvalueDesc>,
+}
+
+/// A captured variable. It points into the VM stack while the variable's
+/// frame is alive and holds the value itself once that frame is gone.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub type UpvalueRef = Rc<RefCell<Upvalue>>;
+
+/// The global variables of a script or module, shared by every closure
+/// created while running it.
+pub type Globals = Rc<RefCell<GlobalScope>>;
+
+#[derive(Debug, Default)]
+pub struct GlobalScope {
+    values: HashMap<String, Value>,
+    /// The globals declared with `const`
+    constants: HashSet<String>,
+}
+
+impl GlobalScope {
+    pub fn new(values: HashMap<String, Value>) -> GlobalScope {
+        GlobalScope {
+            values,
+            constants: HashSet::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+
+    /// Declares a global. The resolver rejects redeclaring a constant within
+    /// one script, so this only fails for a constant defined by an earlier
+    /// run, like a previous line in the REPL.
+    pub fn define(&mut self, name: &str, value: Value, constant: bool) -> Result<()> {
+        if self.constants.contains(name) {
+            return Err(ErrorKind::EvaluateError(format!(
+                "Can't redeclare constant '{}'.",
+                name
+            )));
+        }
+        if constant {
+            self.constants.insert(name.to_owned());
+        }
+        self.values.insert(name.to_owned(), value);
+        Ok(())
+    }
+
+    /// Overwrites an existing global, returning false if there is none.
+    pub fn assign(&mut self, name: &str, value: Value) -> Result<bool> {
+        if self.constants.contains(name) {
+            return Err(ErrorKind::EvaluateError(format!(
+                "Can't assign to constant '{}'.",
+                name
+            )));
+        }
+        Ok(match self.values.get_mut(name) {
+            Some(global) => {
+                *global = value;
+                true
+            }
+            None => false,
+        })
+    }
+}
+
+pub struct Closure {
+    pub proto: Rc<FunctionProto>,
+    pub upvalues: Vec<UpvalueRef>,
+    pub globals: Globals,
+}
+
+impl Closure {
+    pub fn name(&self) -> &str {
+        self.proto.name.as_deref().unwrap_or("script")
+    }
+}
+
+// Upvalues often refer back to the closure itself, so only print the name
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Closure({})", self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}